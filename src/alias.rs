@@ -0,0 +1,169 @@
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    LazyLock,
+};
+
+use anyhow::{bail, Result};
+use dashmap::DashMap;
+use pgrx::{callbacks::PgXactCallbackEvent, pg_sys, pg_sys::panic::ErrorReport, Spi};
+
+use crate::{error::SqlError, generation, storage};
+
+pgrx::extension_sql!(
+    r#"
+CREATE TABLE splade_model_aliases (
+    alias text PRIMARY KEY,
+    model text NOT NULL,
+    updated_at timestamptz NOT NULL DEFAULT now()
+);
+SELECT pg_catalog.pg_extension_config_dump('splade_model_aliases', '');
+"#,
+    name = "splade_model_aliases"
+);
+
+/// Aliases resolved by this backend, with the generation they were read at.
+static ALIASES: LazyLock<DashMap<String, (u64, Option<String>)>> = LazyLock::new(DashMap::new);
+/// Whether `ALIASES` is cleared at the end of the current transaction.
+static CLEAR_REGISTERED: AtomicBool = AtomicBool::new(false);
+
+fn lookup(alias: &str) -> Result<Option<String>> {
+    let Some(aliases) = storage::table("splade_model_aliases")? else {
+        return Ok(None);
+    };
+    let model = Spi::connect(|client| {
+        client
+            .select(
+                &format!("SELECT (SELECT model FROM {} WHERE alias = $1)", aliases),
+                Some(1),
+                &[alias.into()],
+            )?
+            .first()
+            .get_one::<String>()
+    })?;
+    Ok(model)
+}
+
+/// Without shared memory, changes by other sessions cannot be noticed, so
+/// aliases are only kept until the end of the transaction.
+fn clear_at_end_of_transaction() {
+    if CLEAR_REGISTERED.swap(true, Ordering::Relaxed) {
+        return;
+    }
+    for event in [PgXactCallbackEvent::Commit, PgXactCallbackEvent::Abort] {
+        pgrx::register_xact_callback(event, || {
+            ALIASES.clear();
+            CLEAR_REGISTERED.store(false, Ordering::Relaxed);
+        });
+    }
+}
+
+/// Returns the model that `name` refers to, which is `name` itself unless it
+/// is an alias.
+pub fn resolve(name: &str) -> Result<String> {
+    if !crate::preloaded() {
+        if !unsafe { pg_sys::IsTransactionState() } {
+            return Ok(lookup(name)?.unwrap_or_else(|| name.to_string()));
+        }
+        clear_at_end_of_transaction();
+    }
+    let current = generation::current(name);
+    let cached = ALIASES
+        .get(name)
+        .filter(|entry| entry.0 == current)
+        .map(|entry| entry.1.clone());
+    let model = match cached {
+        Some(model) => model,
+        None => {
+            let model = lookup(name)?;
+            ALIASES.insert(name.to_string(), (current, model.clone()));
+            model
+        }
+    };
+    Ok(model.unwrap_or_else(|| name.to_string()))
+}
+
+pub fn exists(alias: &str) -> Result<bool> {
+    Ok(lookup(alias)?.is_some())
+}
+
+/// Makes other backends read alias `alias` again once the change is
+/// committed, as they would read the old target before. If it is rolled
+/// back, this backend forgets the target it read in the meantime.
+fn invalidate(alias: &str) {
+    ALIASES.remove(alias);
+    let committed = alias.to_string();
+    pgrx::register_xact_callback(PgXactCallbackEvent::Commit, move || {
+        generation::bump(&committed);
+    });
+    let aborted = alias.to_string();
+    pgrx::register_xact_callback(PgXactCallbackEvent::Abort, move || {
+        ALIASES.remove(&aborted);
+    });
+}
+
+/// Makes `alias` refer to model `model`, replacing its previous target.
+#[pgrx::pg_extern(volatile, strict)]
+fn create_model_alias(alias: &str, model: &str) -> Result<(), ErrorReport> {
+    crate::error::sql(|| {
+        storage::check_model_name(alias)?;
+        storage::check_model_name(model)?;
+        if storage::exists(alias)? {
+            bail!(SqlError::duplicate_object(format!(
+                "Model {} already exists, an alias cannot have its name",
+                alias
+            )));
+        }
+        if exists(model)? {
+            bail!(SqlError::invalid_parameter_value(format!(
+                "{} is an alias, aliases must refer to models",
+                model
+            )));
+        }
+        if !storage::exists(model)? {
+            bail!(SqlError::undefined_object(format!(
+                "Model {} does not exist",
+                model
+            )));
+        }
+        let Some(aliases) = storage::table("splade_model_aliases")? else {
+            bail!("Extension pg_splade is not installed in the current database");
+        };
+        Spi::run_with_args(
+            &format!(
+                "INSERT INTO {} (alias, model) VALUES ($1, $2) \
+             ON CONFLICT (alias) DO UPDATE SET model = EXCLUDED.model, updated_at = now()",
+                aliases
+            ),
+            &[alias.into(), model.into()],
+        )?;
+        invalidate(alias);
+        Ok(())
+    })
+}
+
+#[pgrx::pg_extern(volatile, strict)]
+fn drop_model_alias(alias: &str) -> Result<(), ErrorReport> {
+    crate::error::sql(|| {
+        storage::check_model_name(alias)?;
+        let Some(aliases) = storage::table("splade_model_aliases")? else {
+            bail!("Extension pg_splade is not installed in the current database");
+        };
+        let deleted = Spi::connect_mut(|client| {
+            client
+                .update(
+                    &format!("DELETE FROM {} WHERE alias = $1", aliases),
+                    None,
+                    &[alias.into()],
+                )
+                .map(|table| table.len())
+        })?;
+        if deleted == 0 {
+            bail!(SqlError::undefined_object(format!(
+                "Alias {} does not exist",
+                alias
+            )));
+        }
+        invalidate(alias);
+        Ok(())
+    })
+}
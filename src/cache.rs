@@ -0,0 +1,193 @@
+use std::{
+    ffi::CStr,
+    hash::{DefaultHasher, Hash, Hasher},
+    ptr::null_mut,
+    sync::atomic::{AtomicPtr, AtomicU64, Ordering},
+};
+
+use pgrx::{pg_guard, pg_shmem_init, pg_sys, PgSharedMemoryInitialization};
+
+use crate::{datatype::SparsevecOwned, encode::EncodeKind, generation};
+
+const NAME: &CStr = c"pg_splade cache";
+/// Number of slots an entry can be stored in. The least recently used of
+/// them is replaced.
+const WAYS: usize = 8;
+/// Vectors with more elements are not cached.
+const MAX_NNZ: usize = 512;
+
+#[repr(C)]
+struct Header {
+    uses: AtomicU64,
+    sets: usize,
+}
+
+#[repr(C)]
+struct Slot {
+    /// Hash of the entry, zero if the slot is empty.
+    key: [u64; 2],
+    last_used: AtomicU64,
+    dims: u32,
+    nnz: u32,
+    indexes: [u32; MAX_NNZ],
+    values: [f32; MAX_NNZ],
+}
+
+/// Encoded texts shared by all sessions, in `splade.cache_size` of shared
+/// memory laid out as a header followed by the slots.
+struct Cache {
+    header: AtomicPtr<Header>,
+    lock: AtomicPtr<pg_sys::LWLock>,
+}
+
+static CACHE: Cache = Cache {
+    header: AtomicPtr::new(null_mut()),
+    lock: AtomicPtr::new(null_mut()),
+};
+
+fn sets() -> usize {
+    crate::guc::cache_size().saturating_sub(std::mem::size_of::<Header>())
+        / (WAYS * std::mem::size_of::<Slot>())
+}
+
+fn size() -> usize {
+    std::mem::size_of::<Header>() + sets() * WAYS * std::mem::size_of::<Slot>()
+}
+
+impl PgSharedMemoryInitialization for Cache {
+    fn pg_init(&'static self) {
+        unsafe {
+            pg_sys::RequestAddinShmemSpace(size());
+            pg_sys::RequestNamedLWLockTranche(NAME.as_ptr(), 1);
+        }
+    }
+
+    unsafe fn shmem_init(&'static self) {
+        unsafe {
+            // `AddinShmemInitLock`, which the bindings do not export.
+            let init_lock = &mut (*pg_sys::MainLWLockArray.add(21)).lock;
+            pg_sys::LWLockAcquire(init_lock, pg_sys::LWLockMode::LW_EXCLUSIVE);
+            let mut found = false;
+            let header =
+                pg_sys::ShmemInitStruct(NAME.as_ptr(), size(), &mut found).cast::<Header>();
+            if !found {
+                std::ptr::write_bytes(header.cast::<u8>(), 0, size());
+                (*header).sets = sets();
+            }
+            self.header.store(header, Ordering::Relaxed);
+            let lock = &mut (*pg_sys::GetNamedLWLockTranche(NAME.as_ptr())).lock;
+            self.lock.store(lock, Ordering::Relaxed);
+            pg_sys::LWLockRelease(init_lock);
+        }
+    }
+}
+
+pub fn init() {
+    if sets() == 0 {
+        return;
+    }
+    pg_shmem_init!(CACHE);
+}
+
+pub fn enabled() -> bool {
+    attached().is_some()
+}
+
+/// Returns the header and slots of the cache, if it is enabled.
+fn attached() -> Option<(&'static Header, *mut Slot, *mut pg_sys::LWLock)> {
+    let header = CACHE.header.load(Ordering::Relaxed);
+    if header.is_null() {
+        return None;
+    }
+    let slots = unsafe { header.add(1).cast::<Slot>() };
+    Some((
+        unsafe { &*header },
+        slots,
+        CACHE.lock.load(Ordering::Relaxed),
+    ))
+}
+
+/// Hashes text `text` encoded as `kind` by the current generation of model
+/// `model` of the current database, as databases can have different models
+/// of the same name. Unless `exact` is set, runs of whitespace are treated as
+/// one space, as tokenizers split words on them anyway.
+fn key(model: &str, kind: EncodeKind, text: &str, exact: bool) -> [u64; 2] {
+    let database = unsafe { pg_sys::MyDatabaseId };
+    let hash = |seed: u64| {
+        let mut hasher = DefaultHasher::new();
+        seed.hash(&mut hasher);
+        database.as_u32().hash(&mut hasher);
+        model.hash(&mut hasher);
+        generation::current(model).hash(&mut hasher);
+        kind.hash(&mut hasher);
+        if exact {
+            text.hash(&mut hasher);
+        } else {
+            for word in text.split_whitespace() {
+                word.hash(&mut hasher);
+            }
+        }
+        hasher.finish()
+    };
+    // An empty slot has a zero key.
+    [hash(0) | 1, hash(1)]
+}
+
+/// Returns the first of the slots that entry `key` can be stored in.
+fn set(header: &Header, slots: *mut Slot, key: [u64; 2]) -> *mut Slot {
+    let set = (key[1] % header.sets as u64) as usize;
+    unsafe { slots.add(set * WAYS) }
+}
+
+/// Returns the vector of `text` encoded as `kind` by model `model`, if it is
+/// cached.
+pub fn get(model: &str, kind: EncodeKind, text: &str, exact: bool) -> Option<SparsevecOwned> {
+    let (header, slots, lock) = attached()?;
+    let key = key(model, kind, text, exact);
+    unsafe { pg_sys::LWLockAcquire(lock, pg_sys::LWLockMode::LW_SHARED) };
+    let ways = unsafe { std::slice::from_raw_parts(set(header, slots, key), WAYS) };
+    let vector = ways.iter().find(|slot| slot.key == key).map(|slot| {
+        let tick = header.uses.fetch_add(1, Ordering::Relaxed) + 1;
+        slot.last_used.store(tick, Ordering::Relaxed);
+        let nnz = slot.nnz as usize;
+        // Entries are only written from valid vectors.
+        unsafe {
+            SparsevecOwned::new_unchecked(
+                slot.dims,
+                slot.indexes[..nnz].to_vec(),
+                slot.values[..nnz].to_vec(),
+            )
+        }
+    });
+    unsafe { pg_sys::LWLockRelease(lock) };
+    vector
+}
+
+/// Caches `vector` as `text` encoded as `kind` by model `model`.
+pub fn put(model: &str, kind: EncodeKind, text: &str, exact: bool, vector: &SparsevecOwned) {
+    let Some((header, slots, lock)) = attached() else {
+        return;
+    };
+    let vector = vector.as_borrowed();
+    if vector.len() > MAX_NNZ {
+        return;
+    }
+    let key = key(model, kind, text, exact);
+    unsafe { pg_sys::LWLockAcquire(lock, pg_sys::LWLockMode::LW_EXCLUSIVE) };
+    let ways = unsafe { std::slice::from_raw_parts_mut(set(header, slots, key), WAYS) };
+    let slot = match ways.iter().position(|slot| slot.key == key) {
+        Some(i) => &mut ways[i],
+        None => ways
+            .iter_mut()
+            .min_by_key(|slot| slot.last_used.load(Ordering::Relaxed))
+            .unwrap(),
+    };
+    slot.key = key;
+    let tick = header.uses.fetch_add(1, Ordering::Relaxed) + 1;
+    slot.last_used.store(tick, Ordering::Relaxed);
+    slot.dims = vector.dims();
+    slot.nnz = vector.len() as u32;
+    slot.indexes[..vector.len()].copy_from_slice(vector.indexes());
+    slot.values[..vector.len()].copy_from_slice(vector.values());
+    unsafe { pg_sys::LWLockRelease(lock) };
+}
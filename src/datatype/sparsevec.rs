@@ -1,5 +1,7 @@
 use anyhow::Result;
 
+use crate::error::SqlError;
+
 pub const MAX_DIM: usize = 1000000000;
 pub const MAX_NNZ: usize = 16000;
 
@@ -52,18 +54,22 @@ impl SparsevecOwned {
 
     pub fn from_dense(dense: &[f32]) -> Result<Self> {
         if !(1..=MAX_DIM).contains(&dense.len()) {
-            anyhow::bail!(
+            anyhow::bail!(SqlError::program_limit_exceeded(format!(
                 "sparsevec dims must be in the range [1, {}], but got {}",
                 MAX_DIM,
                 dense.len()
-            );
+            )));
         }
 
         let mut indexes = Vec::new();
         let mut values = Vec::new();
         for (i, &v) in dense.iter().enumerate() {
             if v.is_nan() || v.is_infinite() {
-                anyhow::bail!("dense vector contains invalid value: {}", v);
+                // Only models produce dense vectors.
+                anyhow::bail!(SqlError::external_routine_exception(format!(
+                    "dense vector contains invalid value: {}",
+                    v
+                )));
             }
             if v != 0.0 {
                 indexes.push(i as u32);
@@ -126,41 +132,52 @@ impl<'a> SparsevecBorrowed<'a> {
 
 fn check_sparsevec(dims: u32, indexes: &[u32], values: &[f32]) -> Result<()> {
     if !(1..=MAX_DIM as u32).contains(&dims) {
-        anyhow::bail!(
+        anyhow::bail!(SqlError::invalid_parameter_value(format!(
             "sparsevec dims must be in the range [1, {}], but got {}",
-            MAX_DIM,
-            dims
-        );
+            MAX_DIM, dims
+        )));
     }
     if indexes.len() != values.len() {
-        anyhow::bail!(
+        anyhow::bail!(SqlError::invalid_parameter_value(format!(
             "index and value must have the same length, but got {} and {}",
             indexes.len(),
             values.len()
-        );
+        )));
     }
     if indexes.len() > MAX_NNZ {
-        anyhow::bail!("sparsevec is too large");
+        anyhow::bail!(SqlError::program_limit_exceeded(format!(
+            "sparsevec is too large, it can have at most {} elements",
+            MAX_NNZ
+        )));
     }
     let len = indexes.len();
     for i in 1..len {
         if indexes[i] <= indexes[i - 1] {
-            anyhow::bail!("index must be sorted, but got {:?}", indexes);
+            anyhow::bail!(SqlError::invalid_parameter_value(format!(
+                "index must be sorted, but got {:?}",
+                indexes
+            )));
         }
     }
     if len != 0 && indexes[len - 1] >= dims {
-        anyhow::bail!(
+        anyhow::bail!(SqlError::invalid_parameter_value(format!(
             "index must be less than dims, but got {} and {}",
             indexes[len - 1],
             dims
-        );
+        )));
     }
     for val in values {
         if val.is_nan() || val.is_infinite() {
-            anyhow::bail!("value must not be NaN or infinite, but got {:?}", values);
+            anyhow::bail!(SqlError::invalid_parameter_value(format!(
+                "value must not be NaN or infinite, but got {:?}",
+                values
+            )));
         }
         if *val == 0.0 {
-            anyhow::bail!("value must not be zero, but got {:?}", values);
+            anyhow::bail!(SqlError::invalid_parameter_value(format!(
+                "value must not be zero, but got {:?}",
+                values
+            )));
         }
     }
 
@@ -0,0 +1,314 @@
+use std::{
+    collections::HashMap,
+    fs::{File, OpenOptions},
+    io::{ErrorKind, Read, Write},
+    path::Path,
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, bail, Result};
+use hf_hub::api::sync::{Api, ApiRepo};
+use ureq::Error;
+
+use crate::{error::SqlError, guc::ModelStorage};
+
+const CHUNK_SIZE: usize = 64 * 1024;
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(30);
+/// Longest a request receives a file for. Larger files are resumed by
+/// further requests, so that a stalled connection cannot block forever.
+const BODY_TIMEOUT: Duration = Duration::from_secs(300);
+/// Attempts of a transfer that fail without receiving anything.
+const MAX_ATTEMPTS: u32 = 5;
+const NOTICE_INTERVAL: Duration = Duration::from_secs(5);
+const SOURCE_FILE: &str = ".source";
+const PICKLE_FILE: &str = "pytorch_model.bin";
+const SAFETENSORS_FILE: &str = "model.safetensors";
+const SAFETENSORS_INDEX_FILE: &str = "model.safetensors.index.json";
+
+/// Settings of the session that started a download, captured so that a
+/// background worker downloads the model the same way.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DownloadOptions {
+    pub storage: ModelStorage,
+    pub allow_pickle_weights: bool,
+}
+
+impl DownloadOptions {
+    pub fn from_gucs() -> Self {
+        Self {
+            storage: crate::guc::model_storage(),
+            allow_pickle_weights: crate::guc::allow_pickle_weights(),
+        }
+    }
+}
+
+pub trait Progress {
+    /// Called after every chunk written to disk. Returning an error aborts the
+    /// download, keeping the partial file so that the next attempt resumes it.
+    fn update(&mut self, file: &str, downloaded: u64, total: Option<u64>) -> Result<()>;
+}
+
+/// Reports progress of a download in the client session as NOTICEs, and
+/// honours query cancellation and `statement_timeout` between chunks.
+pub struct NoticeProgress {
+    last_notice: Instant,
+}
+
+impl NoticeProgress {
+    pub fn new() -> Self {
+        Self {
+            last_notice: Instant::now(),
+        }
+    }
+}
+
+impl Default for NoticeProgress {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Progress for NoticeProgress {
+    fn update(&mut self, file: &str, downloaded: u64, total: Option<u64>) -> Result<()> {
+        pgrx::check_for_interrupts!();
+        if self.last_notice.elapsed() >= NOTICE_INTERVAL {
+            pgrx::notice!("{}", format_progress(file, downloaded, total));
+            self.last_notice = Instant::now();
+        }
+        Ok(())
+    }
+}
+
+pub fn format_progress(file: &str, downloaded: u64, total: Option<u64>) -> String {
+    match total {
+        Some(total) if total > 0 => format!(
+            "downloading {}: {}/{} bytes ({}%)",
+            file,
+            downloaded,
+            total,
+            downloaded * 100 / total
+        ),
+        _ => format!("downloading {}: {} bytes", file, downloaded),
+    }
+}
+
+/// Downloads the model `repo_id` from Hugging Face Hub into `staging`.
+///
+/// If the download fails, the staging directory is kept and a later call for
+/// the same repo resumes the partially downloaded files.
+pub fn download_model(
+    repo_id: &str,
+    staging: &Path,
+    options: DownloadOptions,
+    progress: &mut dyn Progress,
+) -> Result<()> {
+    prepare_staging(staging, repo_id)?;
+
+    let api = Api::new()?;
+    let repo = api.model(repo_id.to_string());
+
+    fetch(&repo, "config.json", staging, progress)?;
+    // Only inference-free models publish an IDF table.
+    fetch_optional(&repo, "idf.json", staging, progress)?;
+    // Older BERT checkpoints only ship the WordPiece vocabulary.
+    if !fetch_optional(&repo, "tokenizer.json", staging, progress)? {
+        fetch(&repo, "vocab.txt", staging, progress)?;
+        for file in ["tokenizer_config.json", "special_tokens_map.json"] {
+            fetch_optional(&repo, file, staging, progress)?;
+        }
+    }
+    if fetch_optional(&repo, SAFETENSORS_FILE, staging, progress)? {
+        return Ok(());
+    }
+    if fetch_optional(&repo, SAFETENSORS_INDEX_FILE, staging, progress)? {
+        let shards = crate::model::safetensors_shards(staging)?;
+        for shard in shards {
+            fetch(&repo, &shard, staging, progress)
+                .map_err(|e| anyhow!("Failed to download model file {}: {}", shard, e))?;
+        }
+        return Ok(());
+    }
+    if !options.allow_pickle_weights {
+        bail!(SqlError::invalid_parameter_value(format!(
+            "Repo {} has no {}",
+            repo_id, SAFETENSORS_FILE
+        ))
+        .with_hint(format!(
+            "Set splade.allow_pickle_weights to accept {}",
+            PICKLE_FILE
+        )));
+    }
+    if !fetch_optional(&repo, PICKLE_FILE, staging, progress)? {
+        bail!(SqlError::invalid_parameter_value("No model file found"));
+    }
+    convert_pickle(staging)
+}
+
+/// Converts `pytorch_model.bin` in `dir` into `model.safetensors`, so that
+/// the pickle is parsed once at install time instead of on every load.
+fn convert_pickle(dir: &Path) -> Result<()> {
+    let tensors = candle_core::pickle::read_all(dir.join(PICKLE_FILE))
+        .map_err(|e| anyhow!("Failed to read {}: {}", PICKLE_FILE, e))?
+        .into_iter()
+        .collect::<HashMap<_, _>>();
+    let partial = dir.join(format!("{}.part", SAFETENSORS_FILE));
+    candle_core::safetensors::save(&tensors, &partial)?;
+    File::open(&partial)?.sync_all()?;
+    std::fs::rename(&partial, dir.join(SAFETENSORS_FILE))?;
+    std::fs::remove_file(dir.join(PICKLE_FILE))?;
+    Ok(())
+}
+
+/// Creates the staging directory, or reuses it if it was left behind by an
+/// earlier attempt to download the same repo.
+fn prepare_staging(staging: &Path, repo_id: &str) -> Result<()> {
+    let source = staging.join(SOURCE_FILE);
+    if staging.exists() {
+        match std::fs::read_to_string(&source) {
+            Ok(s) if s == repo_id => return Ok(()),
+            _ => std::fs::remove_dir_all(staging)?,
+        }
+    }
+    std::fs::create_dir_all(staging)?;
+    std::fs::write(&source, repo_id)?;
+    Ok(())
+}
+
+pub fn is_not_found(e: &anyhow::Error) -> bool {
+    matches!(e.downcast_ref::<Error>(), Some(Error::StatusCode(404)))
+}
+
+/// Like `fetch`, but returns `false` instead of failing if the repo does not
+/// have `file`.
+fn fetch_optional(
+    repo: &ApiRepo,
+    file: &str,
+    dir: &Path,
+    progress: &mut dyn Progress,
+) -> Result<bool> {
+    match fetch(repo, file, dir, progress) {
+        Ok(()) => Ok(true),
+        Err(e) if is_not_found(&e) => Ok(false),
+        Err(e) => Err(anyhow!("Failed to download model file {}: {}", file, e)),
+    }
+}
+
+/// A transfer that failed on the network, and can be resumed.
+#[derive(Debug)]
+struct Stalled(std::io::Error);
+
+impl std::fmt::Display for Stalled {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for Stalled {}
+
+/// Downloads `file` of `repo` into `dir`, resuming from `<file>.part` if a
+/// previous attempt was interrupted. Files that are already complete are
+/// skipped. Transfers that fail on the network are resumed, as long as they
+/// make progress.
+pub fn fetch(repo: &ApiRepo, file: &str, dir: &Path, progress: &mut dyn Progress) -> Result<()> {
+    let partial = dir.join(format!("{}.part", file));
+    let size = || std::fs::metadata(&partial).map(|m| m.len()).unwrap_or(0);
+    let mut attempts = 0;
+    loop {
+        let before = size();
+        match fetch_once(repo, file, dir, progress) {
+            Err(e) if e.is::<Stalled>() => {
+                attempts = if size() > before { 1 } else { attempts + 1 };
+                if attempts >= MAX_ATTEMPTS {
+                    return Err(e);
+                }
+                pgrx::check_for_interrupts!();
+            }
+            result => return result,
+        }
+    }
+}
+
+fn fetch_once(repo: &ApiRepo, file: &str, dir: &Path, progress: &mut dyn Progress) -> Result<()> {
+    let dest = dir.join(file);
+    if dest.exists() {
+        return Ok(());
+    }
+    let partial = dir.join(format!("{}.part", file));
+    let offset = std::fs::metadata(&partial).map(|m| m.len()).unwrap_or(0);
+
+    let url = repo.url(file);
+    let mut request = ureq::get(&url)
+        .config()
+        .timeout_connect(Some(RESPONSE_TIMEOUT))
+        .timeout_recv_response(Some(RESPONSE_TIMEOUT))
+        .timeout_recv_body(Some(BODY_TIMEOUT))
+        .build();
+    if offset > 0 {
+        request = request.header("Range", format!("bytes={}-", offset));
+    }
+    let mut res = match request.call() {
+        Err(Error::StatusCode(416)) => {
+            // The partial file does not match the remote file anymore.
+            std::fs::remove_file(&partial)?;
+            return fetch_once(repo, file, dir, progress);
+        }
+        Err(Error::Timeout(_)) | Err(Error::Io(_)) => {
+            bail!(Stalled(std::io::Error::new(
+                ErrorKind::TimedOut,
+                format!("No response for {}", url)
+            )))
+        }
+        res => res?,
+    };
+
+    let resumed = offset > 0 && res.status() == 206;
+    let (mut downloaded, total) = if resumed {
+        let total = res
+            .headers()
+            .get("Content-Range")
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.rsplit('/').next())
+            .and_then(|v| v.parse::<u64>().ok())
+            .or(res.body().content_length().map(|len| len + offset));
+        (offset, total)
+    } else {
+        (0, res.body().content_length())
+    };
+
+    let mut output = if resumed {
+        OpenOptions::new().append(true).open(&partial)?
+    } else {
+        File::create(&partial)?
+    };
+    let mut reader = res.body_mut().as_reader();
+    let mut buf = vec![0u8; CHUNK_SIZE];
+    loop {
+        let n = match reader.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => {
+                // Keep what was received, so that the next attempt resumes it.
+                output.sync_all()?;
+                bail!(Stalled(e));
+            }
+        };
+        output.write_all(&buf[..n])?;
+        downloaded += n as u64;
+        progress.update(file, downloaded, total)?;
+    }
+    output.sync_all()?;
+
+    if let Some(total) = total {
+        if downloaded != total {
+            bail!(
+                "Incomplete download of {}: got {} of {} bytes",
+                file,
+                downloaded,
+                total
+            );
+        }
+    }
+    std::fs::rename(&partial, &dest)?;
+    Ok(())
+}
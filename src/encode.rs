@@ -1,159 +1,555 @@
-use std::{ffi::CStr, path::PathBuf, sync::LazyLock};
+use std::{
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        LazyLock,
+    },
+    time::{Duration, Instant},
+};
 
 use anyhow::Result;
-use dashmap::DashMap;
-use hf_hub::api::sync::Api;
+use dashmap::{DashMap, DashSet};
+use pgrx::{iter::TableIterator, name, pg_sys, pg_sys::panic::ErrorReport, PgXactCallbackEvent};
 
 use crate::{
+    alias, cache,
     datatype::{SparsevecOutput, SparsevecOwned},
-    model::{load_dynamic_model, ModelPtr},
+    download::{self, DownloadOptions, NoticeProgress, Progress},
+    error::{model_not_found, SqlError},
+    generation,
+    guc::QueryMode,
+    model::{load_dynamic_model, validate_model, ModelPtr, Profile},
+    remote, stats, storage, worker,
 };
 
-static ASSETS_DIR: LazyLock<PathBuf> = LazyLock::new(|| {
-    let mut sharepath = [0u8; pgrx::pg_sys::MAXPGPATH as usize];
-    unsafe {
-        #[allow(static_mut_refs)]
-        pgrx::pg_sys::get_share_path(
-            pgrx::pg_sys::my_exec_path.as_ptr(),
-            sharepath.as_mut_ptr().cast(),
-        )
-    };
-    let sharepath = CStr::from_bytes_until_nul(&sharepath).unwrap();
-    let sharepath = sharepath.to_str().unwrap();
-    let mut res = PathBuf::from(sharepath);
-    res.push("splade");
-    res
-});
-
-type ModelObjectPool = DashMap<String, ModelPtr>;
+/// A model loaded in this process.
+struct LoadedModel {
+    name: String,
+    path: PathBuf,
+    /// Generation of the model when it was loaded.
+    generation: u64,
+    model: ModelPtr,
+    /// Size of the model files, as an estimate of the memory it uses.
+    size: u64,
+    /// When the model was last used, to evict the least recently used first.
+    last_used: u64,
+}
+
+type ModelObjectPool = DashMap<String, LoadedModel>;
 static TOKENIZER_OBJECT_POOL: LazyLock<ModelObjectPool> = LazyLock::new(ModelObjectPool::new);
+static USES: AtomicU64 = AtomicU64::new(0);
+/// Models changed by the current transaction.
+static PENDING: LazyLock<DashSet<String>> = LazyLock::new(DashSet::new);
+/// Whether models are remote, with the generation they were checked at.
+static REMOTE: LazyLock<DashMap<String, (u64, bool)>> = LazyLock::new(DashMap::new);
 
-fn get_model(model: &str) -> Result<ModelPtr> {
-    match TOKENIZER_OBJECT_POOL.get(model) {
-        Some(ptr) => Ok(ptr.clone()),
-        None => {
-            let model_path = ASSETS_DIR.join(model);
-            if !model_path.exists() {
-                return Err(anyhow::anyhow!("Model {} not found", model));
-            }
-            let ptr = load_dynamic_model(&model_path)?;
-            TOKENIZER_OBJECT_POOL.insert(model.to_string(), ptr.clone());
-            Ok(ptr)
+fn next_use() -> u64 {
+    USES.fetch_add(1, Ordering::Relaxed)
+}
+
+pub(crate) fn get_model(model: &str) -> Result<ModelPtr> {
+    get_cached_model(model, model, || storage::locate(model))
+}
+
+/// Returns model `model` if it is stored on the filesystem, for processes
+/// that are not connected to a database.
+pub(crate) fn get_filesystem_model(model: &str) -> Result<ModelPtr> {
+    get_cached_model(model, model, || storage::find_model_dir(model))
+}
+
+/// Returns model `model` loaded from `path`, for processes that cannot look
+/// models up in the database themselves. They serve several databases, which
+/// can have different models of the same name, so models are kept by path.
+pub(crate) fn get_model_at(model: &str, path: &Path) -> Result<ModelPtr> {
+    get_cached_model(model, &path.to_string_lossy(), || {
+        Ok(Some(path.to_path_buf()))
+    })
+}
+
+/// Returns model `model` stored on the filesystem, kept by path like
+/// `get_model_at`, for processes that preload models before serving them.
+pub(crate) fn get_filesystem_model_at(model: &str) -> Result<ModelPtr> {
+    let Some(path) = storage::find_model_dir(model)? else {
+        return Err(model_not_found(model).into());
+    };
+    get_model_at(model, &path)
+}
+
+/// Returns model `model`, kept in the pool as `key`.
+fn get_cached_model(
+    model: &str,
+    key: &str,
+    locate: impl FnOnce() -> Result<Option<PathBuf>>,
+) -> Result<ModelPtr> {
+    let current = generation::current(model);
+    let cached = TOKENIZER_OBJECT_POOL
+        .get_mut(key)
+        .filter(|entry| entry.generation == current)
+        .map(|mut entry| {
+            entry.last_used = next_use();
+            entry.model.clone()
+        });
+    if let Some(ptr) = cached {
+        return Ok(ptr);
+    }
+    // Copies of older generations of the model are never used again.
+    TOKENIZER_OBJECT_POOL.retain(|_, entry| entry.name != model || entry.generation == current);
+    let Some(model_path) = locate()? else {
+        return Err(model_not_found(model).into());
+    };
+    // A copy kept under another key, like the ones a worker inherits from the
+    // postmaster, is moved rather than loaded again.
+    let old_key = TOKENIZER_OBJECT_POOL
+        .iter()
+        .find(|entry| entry.name == model && entry.path == model_path)
+        .map(|entry| entry.key().clone());
+    if let Some((_, mut entry)) = old_key.and_then(|old| TOKENIZER_OBJECT_POOL.remove(&old)) {
+        entry.last_used = next_use();
+        let ptr = entry.model.clone();
+        TOKENIZER_OBJECT_POOL.insert(key.to_string(), entry);
+        return Ok(ptr);
+    }
+    let size = model_size(&model_path)?;
+    reserve(model, size)?;
+    let start = Instant::now();
+    let ptr = load_dynamic_model(&model_path).map_err(|e| {
+        SqlError::external_routine_exception(format!("Failed to load model {}: {}", model, e))
+            .with_hint("Check the files of the model with splade_validate_model")
+    })?;
+    stats::record_load(model, start.elapsed());
+    TOKENIZER_OBJECT_POOL.insert(
+        key.to_string(),
+        LoadedModel {
+            name: model.to_string(),
+            path: model_path,
+            generation: current,
+            model: ptr.clone(),
+            size,
+            last_used: next_use(),
+        },
+    );
+    Ok(ptr)
+}
+
+/// Returns the size of the files of the model in `path`.
+fn model_size(path: &Path) -> Result<u64> {
+    let mut size = 0;
+    for entry in std::fs::read_dir(path)? {
+        let metadata = entry?.metadata()?;
+        if metadata.is_file() {
+            size += metadata.len();
         }
     }
+    Ok(size)
 }
 
+/// Evicts the least recently used models of this process until model `model`
+/// of `size` bytes fits in `splade.max_model_memory`.
+fn reserve(model: &str, size: u64) -> Result<()> {
+    let Some(budget) = crate::guc::max_model_memory() else {
+        return Ok(());
+    };
+    let mb = |bytes: u64| bytes.div_ceil(1024 * 1024);
+    if size > budget {
+        return Err(SqlError::program_limit_exceeded(format!(
+            "Model {} needs {} MB of memory, more than splade.max_model_memory ({} MB)",
+            model,
+            mb(size),
+            mb(budget)
+        ))
+        .with_hint("Raise splade.max_model_memory")
+        .into());
+    }
+    loop {
+        let used = TOKENIZER_OBJECT_POOL
+            .iter()
+            .map(|entry| entry.size)
+            .sum::<u64>();
+        if used + size <= budget {
+            return Ok(());
+        }
+        let lru = TOKENIZER_OBJECT_POOL
+            .iter()
+            .min_by_key(|entry| entry.last_used)
+            .map(|entry| entry.key().clone());
+        let Some(lru) = lru else {
+            return Ok(());
+        };
+        TOKENIZER_OBJECT_POOL.remove(&lru);
+    }
+}
+
+/// Drops the copies of model `name` loaded in this backend, so that the next
+/// use loads it from storage again. Returns whether it was loaded.
+pub(crate) fn evict(name: &str) -> bool {
+    let loaded = TOKENIZER_OBJECT_POOL.len();
+    TOKENIZER_OBJECT_POOL.retain(|_, entry| entry.name != name);
+    TOKENIZER_OBJECT_POOL.len() < loaded
+}
+
+/// Drops the copies of model `name` loaded in all backends, after it was
+/// replaced or deleted. Other backends would load the old model again until
+/// the change is committed, so the generation is bumped on commit.
+pub(crate) fn invalidate(name: &str) {
+    evict(name);
+    if !unsafe { pg_sys::IsTransactionState() } {
+        generation::bump(name);
+        return;
+    }
+    // Until then, this backend must not share what it encodes with the
+    // uncommitted model.
+    PENDING.insert(name.to_string());
+    let committed = name.to_string();
+    pgrx::register_xact_callback(PgXactCallbackEvent::Commit, move || {
+        PENDING.remove(&committed);
+        generation::bump(&committed);
+    });
+    let aborted = name.to_string();
+    pgrx::register_xact_callback(PgXactCallbackEvent::Abort, move || {
+        PENDING.remove(&aborted);
+        evict(&aborted);
+    });
+}
+
+/// Loads the models of `splade.preload_models` in the postmaster, so that
+/// all connections share them. They are warmed up by the preload worker, as
+/// encoding here would start thread pools that forked children inherit.
 pub fn init() {
-    // Preload models
-    let models = crate::guc::preload_models();
-    for model in models {
+    for model in crate::guc::preload_models() {
         if let Err(e) = get_model(&model) {
             pgrx::warning!("Failed to load model {}: {}", model, e);
         }
     }
 }
 
-#[pgrx::pg_extern(immutable, strict, parallel_safe)]
-fn encode_document(document: &str, model: &str) -> Result<SparsevecOutput> {
-    let model = get_model(model)?;
-    let tensor = model.encode_document(document)?;
-    let vec = tensor.to_vec1::<f32>()?;
-    let sparse_vec = SparsevecOwned::from_dense(&vec)?;
-    let output = SparsevecOutput::new(sparse_vec.as_borrowed());
-    Ok(output)
+/// Loads the models of `splade.preload_models` with `load`, and encodes a
+/// short text with each so that the first query does not page in weights.
+pub(crate) fn preload(load: impl Fn(&str) -> Result<ModelPtr>) {
+    for model in crate::guc::preload_models() {
+        let result = load(&model).and_then(|ptr| ptr.encode_document("warm up"));
+        if let Err(e) = result {
+            pgrx::warning!("Failed to preload model {}: {}", model, e);
+        }
+    }
+}
+
+/// What a text is encoded as.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum EncodeKind {
+    Document,
+    Query(QueryMode),
+}
+
+/// Encodes `text` with model `name` loaded in this process.
+pub(crate) fn encode_with(
+    name: &str,
+    model: &ModelPtr,
+    text: &str,
+    kind: EncodeKind,
+) -> Result<SparsevecOwned> {
+    let start = Instant::now();
+    let encoded = match kind {
+        EncodeKind::Document => model.encode_document(text),
+        EncodeKind::Query(mode) => model.encode_query(text, mode),
+    }
+    .map_err(SqlError::external)?;
+    let vec = encoded.vector.to_vec1::<f32>()?;
+    stats::record_encode(
+        name,
+        kind,
+        1,
+        encoded.tokens,
+        encoded.truncated as usize,
+        start.elapsed(),
+    );
+    SparsevecOwned::from_dense(&vec)
+}
+
+/// Encodes `texts` together with a model loaded in this process. If the batch
+/// fails, the texts are encoded one by one so that only the failing ones
+/// return errors.
+pub(crate) fn encode_batch_with(
+    name: &str,
+    model: &ModelPtr,
+    texts: &[&str],
+    kind: EncodeKind,
+) -> Vec<Result<SparsevecOwned>> {
+    let start = Instant::now();
+    let encoded = match kind {
+        EncodeKind::Document => model.encode_documents(texts),
+        EncodeKind::Query(mode) => model.encode_queries(texts, mode),
+    };
+    match encoded {
+        Ok(encoded) => {
+            stats::record_encode(
+                name,
+                kind,
+                texts.len(),
+                encoded.iter().map(|e| e.tokens).sum(),
+                encoded.iter().filter(|e| e.truncated).count(),
+                start.elapsed(),
+            );
+            encoded
+                .into_iter()
+                .map(|e| SparsevecOwned::from_dense(&e.vector.to_vec1::<f32>()?))
+                .collect()
+        }
+        Err(_) => texts
+            .iter()
+            .map(|text| {
+                pgrx::check_for_interrupts!();
+                encode_with(name, model, text, kind)
+            })
+            .collect(),
+    }
+}
+
+/// Rejects texts longer than `splade.max_input_bytes`, which would take long
+/// to tokenize only for most of them to be truncated.
+fn check_input(text: &str) -> Result<()> {
+    match crate::guc::max_input_bytes() {
+        Some(max) if text.len() > max => Err(SqlError::program_limit_exceeded(format!(
+            "Text of {} bytes exceeds splade.max_input_bytes ({} bytes)",
+            text.len(),
+            max
+        ))
+        .with_hint("Split the text, or raise splade.max_input_bytes")
+        .into()),
+        _ => Ok(()),
+    }
+}
+
+/// Whether model `model` is served by a remote endpoint, which receives texts
+/// as they are.
+fn is_remote(model: &str) -> Result<bool> {
+    let current = generation::current(model);
+    if let Some(entry) = REMOTE.get(model).filter(|entry| entry.0 == current) {
+        return Ok(entry.1);
+    }
+    let remote =
+        storage::locate(model)?.is_some_and(|path| path.join(remote::CONFIG_FILE).exists());
+    REMOTE.insert(model.to_string(), (current, remote));
+    Ok(remote)
+}
+
+fn encode(text: &str, model: &str, kind: EncodeKind) -> Result<SparsevecOutput> {
+    check_input(text)?;
+    let model = &alias::resolve(model)?;
+    let cached = cache::enabled() && !PENDING.contains(model);
+    // Only tokenizers are known to ignore differences in whitespace.
+    let exact = cached && is_remote(model)?;
+    if let Some(sparse_vec) = cached
+        .then(|| cache::get(model, kind, text, exact))
+        .flatten()
+    {
+        return Ok(SparsevecOutput::new(sparse_vec.as_borrowed()));
+    }
+    let sparse_vec = if worker::enabled() {
+        worker::encode(model, text, kind)?
+    } else {
+        encode_with(model, &get_model(model)?, text, kind)?
+    };
+    if cached {
+        cache::put(model, kind, text, exact, &sparse_vec);
+    }
+    Ok(SparsevecOutput::new(sparse_vec.as_borrowed()))
 }
 
 #[pgrx::pg_extern(immutable, strict, parallel_safe)]
-fn encode_query(query: &str, model: &str) -> Result<SparsevecOutput> {
-    let model = get_model(model)?;
-    let tensor = model.encode_query(query)?;
-    let vec = tensor.to_vec1::<f32>()?;
-    let sparse_vec = SparsevecOwned::from_dense(&vec)?;
-    let output = SparsevecOutput::new(sparse_vec.as_borrowed());
-    Ok(output)
+fn encode_document(document: &str, model: &str) -> Result<SparsevecOutput, ErrorReport> {
+    crate::error::sql(|| encode(document, model, EncodeKind::Document))
+}
+
+#[pgrx::pg_extern(stable, strict, parallel_safe)]
+fn encode_query(query: &str, model: &str) -> Result<SparsevecOutput, ErrorReport> {
+    crate::error::sql(|| encode(query, model, EncodeKind::Query(crate::guc::query_mode())))
+}
+
+fn default_model() -> Result<String> {
+    crate::guc::default_model().ok_or(
+        SqlError::undefined_object("No model given and splade.default_model is not set")
+            .with_hint("Pass the model as the second argument, or set splade.default_model")
+            .into(),
+    )
+}
+
+#[pgrx::pg_extern(name = "encode_document", stable, strict, parallel_safe)]
+fn encode_document_default(document: &str) -> Result<SparsevecOutput, ErrorReport> {
+    crate::error::sql(|| encode(document, &default_model()?, EncodeKind::Document))
+}
+
+#[pgrx::pg_extern(name = "encode_query", stable, strict, parallel_safe)]
+fn encode_query_default(query: &str) -> Result<SparsevecOutput, ErrorReport> {
+    crate::error::sql(|| {
+        encode(
+            query,
+            &default_model()?,
+            EncodeKind::Query(crate::guc::query_mode()),
+        )
+    })
+}
+
+pub(crate) fn check_model_absent(name: &str) -> Result<()> {
+    if storage::exists(name)? {
+        return Err(SqlError::duplicate_object(format!("Model {} already exists", name)).into());
+    }
+    if alias::exists(name)? {
+        return Err(SqlError::duplicate_object(format!(
+            "Name {} is already used by an alias",
+            name
+        ))
+        .into());
+    }
+    Ok(())
+}
+
+/// Downloads the model `repo_id` from Hugging Face Hub as model `name`.
+pub(crate) fn download(
+    name: &str,
+    repo_id: &str,
+    options: DownloadOptions,
+    progress: &mut dyn Progress,
+) -> Result<()> {
+    let staging = storage::staging_dir(name)?;
+    check_model_absent(name)?;
+    download::download_model(repo_id, &staging, options, progress).map_err(SqlError::external)?;
+    storage::install(name, &staging, options.storage)?;
+    invalidate(name);
+    Ok(())
 }
 
 #[pgrx::pg_extern(volatile, strict)]
-fn download_model(name: &str, repo_id: String) -> Result<()> {
-    use ureq::Error;
-
-    let assets_dir = ASSETS_DIR.join(name);
-    if assets_dir.exists() {
-        return Err(anyhow::anyhow!("Model {} already exists", name));
-    }
-    std::fs::create_dir_all(&assets_dir)?;
-
-    let api = Api::new()?;
-    let repo = api.model(repo_id);
-
-    let inner = || -> Result<()> {
-        for file in ["config.json", "idf.json", "tokenizer.json"] {
-            let file_path = assets_dir.join(file);
-            let file_url = repo.url(file);
-            let mut res = ureq::get(&file_url).call()?;
-            let mut reader = res.body_mut().as_reader();
-            let mut file = std::fs::File::create(file_path)?;
-            std::io::copy(&mut reader, &mut file)?;
-        }
-        let mut success = false;
-        for try_file in ["model.safetensors", "pytorch_model.bin"] {
-            let file_path = assets_dir.join(try_file);
-            let file_url = repo.url(try_file);
-            let mut res = ureq::get(&file_url).call();
-            let mut reader = match &mut res {
-                Ok(res) => res.body_mut().as_reader(),
-                Err(Error::StatusCode(404)) => {
-                    continue;
-                }
-                Err(e) => {
-                    return Err(anyhow::anyhow!(
-                        "Failed to download model file {}: {}",
-                        try_file,
-                        e
-                    ));
-                }
-            };
-            std::io::copy(&mut reader, &mut std::fs::File::create(file_path)?)?;
-            success = true;
-            break;
-        }
-        if !success {
-            return Err(anyhow::anyhow!("No model file found"));
+fn download_model(name: &str, repo_id: String) -> Result<(), ErrorReport> {
+    crate::error::sql(|| {
+        if crate::job::is_active(name) {
+            return Err(SqlError::object_in_use(format!(
+                "Model {} is already being downloaded",
+                name
+            ))
+            .into());
         }
+        download(
+            name,
+            &repo_id,
+            DownloadOptions::from_gucs(),
+            &mut NoticeProgress::new(),
+        )
+    })
+}
 
+#[pgrx::pg_extern(volatile, strict)]
+fn delete_model(name: &str) -> Result<(), ErrorReport> {
+    crate::error::sql(|| {
+        storage::delete(name)?;
+        invalidate(name);
         Ok(())
-    };
+    })
+}
 
-    match inner() {
-        Ok(_) => Ok(()),
-        Err(e) => {
-            std::fs::remove_dir_all(&assets_dir)?;
-            Err(e)
-        }
-    }
+#[pgrx::pg_extern(volatile, strict)]
+fn unload_model(name: &str) -> Result<bool, ErrorReport> {
+    crate::error::sql(|| {
+        storage::check_model_name(name)?;
+        Ok(evict(name))
+    })
 }
 
 #[pgrx::pg_extern(volatile, strict)]
-fn delete_model(name: &str) -> Result<()> {
-    let assets_dir = ASSETS_DIR.join(name);
-    if !assets_dir.exists() {
-        return Err(anyhow::anyhow!("Model {} does not exist", name));
-    }
-    std::fs::remove_dir_all(&assets_dir)?;
-    Ok(())
+fn reload_model(name: &str) -> Result<(), ErrorReport> {
+    crate::error::sql(|| {
+        storage::check_model_name(name)?;
+        invalidate(name);
+        get_model(name)?;
+        Ok(())
+    })
+}
+
+/// Checks that the files of model `name` are complete and consistent, without
+/// keeping the model loaded.
+#[allow(clippy::type_complexity)]
+#[pgrx::pg_extern(volatile, strict)]
+fn splade_validate_model(
+    name: &str,
+) -> Result<
+    TableIterator<
+        'static,
+        (
+            name!(component, String),
+            name!(ok, bool),
+            name!(detail, String),
+        ),
+    >,
+    ErrorReport,
+> {
+    crate::error::sql(|| {
+        let Some(model_path) = storage::locate(name)? else {
+            return Err(model_not_found(name).into());
+        };
+        let rows = validate_model(&model_path)
+            .into_iter()
+            .map(|(component, result)| match result {
+                Ok(detail) => (component.to_string(), true, detail),
+                Err(e) => (component.to_string(), false, e.to_string()),
+            })
+            .collect::<Vec<_>>();
+        Ok(TableIterator::new(rows))
+    })
 }
 
+/// Encodes `text` as a document `iterations` times with model `model` loaded
+/// in this session, and reports the average and 95th percentile time of each
+/// stage in milliseconds.
+#[allow(clippy::type_complexity)]
 #[pgrx::pg_extern(volatile, strict)]
-fn list_model() -> Vec<String> {
-    let mut models = vec![];
-    if let Ok(entries) = std::fs::read_dir(&*ASSETS_DIR) {
-        for entry in entries.flatten() {
-            if entry.file_type().map(|ft| ft.is_dir()).unwrap_or(false) {
-                if let Some(name) = entry.file_name().to_str() {
-                    models.push(name.to_string());
+fn splade_profile(
+    text: &str,
+    model: &str,
+    iterations: i32,
+) -> Result<
+    TableIterator<'static, (name!(stage, String), name!(avg_ms, f64), name!(p95_ms, f64))>,
+    ErrorReport,
+> {
+    crate::error::sql(|| {
+        if iterations < 1 {
+            return Err(SqlError::invalid_parameter_value("iterations must be positive").into());
+        }
+        check_input(text)?;
+        let model = alias::resolve(model)?;
+        let ptr = get_model(&model)?;
+        // The first run pages in the weights, and is not counted.
+        ptr.encode_document(text)?;
+
+        let mut samples: Vec<(&'static str, Vec<Duration>)> = vec![];
+        for _ in 0..iterations {
+            pgrx::check_for_interrupts!();
+            let mut profile = Profile::new();
+            let start = Instant::now();
+            let encoded = ptr.profile_document(text, &mut profile)?;
+            SparsevecOwned::from_dense(&encoded.vector.to_vec1::<f32>()?)?;
+            profile.finish("from_dense");
+            profile.stages.push(("total", start.elapsed()));
+            for (i, (stage, elapsed)) in profile.stages.into_iter().enumerate() {
+                if samples.len() <= i {
+                    samples.push((stage, vec![]));
                 }
+                samples[i].1.push(elapsed);
             }
         }
-    }
-    models
+        let millis = |d: Duration| d.as_secs_f64() * 1000.0;
+        let rows = samples
+            .into_iter()
+            .map(|(stage, mut durations)| {
+                durations.sort();
+                let avg = durations.iter().sum::<Duration>() / durations.len() as u32;
+                let p95 = durations[(durations.len() * 95).div_ceil(100) - 1];
+                (stage.to_string(), millis(avg), millis(p95))
+            })
+            .collect::<Vec<_>>();
+        Ok(TableIterator::new(rows))
+    })
+}
+
+#[pgrx::pg_extern(volatile, strict)]
+fn list_model() -> Result<Vec<String>, ErrorReport> {
+    crate::error::sql(storage::list)
 }
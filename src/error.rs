@@ -0,0 +1,116 @@
+use std::fmt::{self, Display};
+
+use pgrx::{pg_sys::panic::ErrorReport, PgSqlErrorCode};
+
+/// An error with the SQLSTATE, and the DETAIL and HINT lines, that SQL
+/// functions report it with. Other errors are reported as `data_exception`.
+#[derive(Debug, Clone)]
+pub struct SqlError {
+    pub code: PgSqlErrorCode,
+    pub message: String,
+    pub detail: Option<String>,
+    pub hint: Option<String>,
+}
+
+impl SqlError {
+    pub fn new(code: PgSqlErrorCode, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+            detail: None,
+            hint: None,
+        }
+    }
+
+    /// A model, alias or setting that is referred to does not exist.
+    pub fn undefined_object(message: impl Into<String>) -> Self {
+        Self::new(PgSqlErrorCode::ERRCODE_UNDEFINED_OBJECT, message)
+    }
+
+    pub fn duplicate_object(message: impl Into<String>) -> Self {
+        Self::new(PgSqlErrorCode::ERRCODE_DUPLICATE_OBJECT, message)
+    }
+
+    /// A model is being created by another function or job.
+    pub fn object_in_use(message: impl Into<String>) -> Self {
+        Self::new(PgSqlErrorCode::ERRCODE_OBJECT_IN_USE, message)
+    }
+
+    pub fn invalid_parameter_value(message: impl Into<String>) -> Self {
+        Self::new(PgSqlErrorCode::ERRCODE_INVALID_PARAMETER_VALUE, message)
+    }
+
+    /// An input is larger than pg_splade accepts.
+    pub fn program_limit_exceeded(message: impl Into<String>) -> Self {
+        Self::new(PgSqlErrorCode::ERRCODE_PROGRAM_LIMIT_EXCEEDED, message)
+    }
+
+    /// Loading or running a model failed, or a service it relies on did.
+    pub fn external_routine_exception(message: impl Into<String>) -> Self {
+        Self::new(PgSqlErrorCode::ERRCODE_EXTERNAL_ROUTINE_EXCEPTION, message)
+    }
+
+    pub fn with_detail(mut self, detail: impl Into<String>) -> Self {
+        self.detail = Some(detail.into());
+        self
+    }
+
+    pub fn with_hint(mut self, hint: impl Into<String>) -> Self {
+        self.hint = Some(hint.into());
+        self
+    }
+
+    /// Returns `error` as a SQL error, using the SQLSTATE of the `SqlError`
+    /// it carries if any.
+    pub fn of(error: &anyhow::Error) -> Self {
+        if let Some(e) = error.downcast_ref::<SqlError>() {
+            return e.clone();
+        }
+        // Errors of candle are only raised while running models.
+        if error.downcast_ref::<candle_core::Error>().is_some() {
+            return Self::external_routine_exception(error.to_string());
+        }
+        Self::new(PgSqlErrorCode::ERRCODE_DATA_EXCEPTION, error.to_string())
+    }
+
+    /// Marks `error` as a failure of a model or an external service, unless
+    /// it already has a SQLSTATE.
+    pub fn external(error: anyhow::Error) -> anyhow::Error {
+        if error.is::<SqlError>() {
+            return error;
+        }
+        Self::external_routine_exception(error.to_string()).into()
+    }
+}
+
+impl Display for SqlError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl std::error::Error for SqlError {}
+
+/// Returns the error of model `model` not being found.
+pub fn model_not_found(model: &str) -> SqlError {
+    SqlError::undefined_object(format!("Model {} not found", model))
+        .with_hint("Download it with download_model, or see the available models with list_model()")
+}
+
+/// Runs the body of a SQL function, raising its error with its SQLSTATE.
+pub fn sql<T>(body: impl FnOnce() -> anyhow::Result<T>) -> Result<T, ErrorReport> {
+    body().map_err(report)
+}
+
+/// Converts `error` into the report that SQL functions raise it with.
+pub fn report(error: anyhow::Error) -> ErrorReport {
+    let error = SqlError::of(&error);
+    let mut report = ErrorReport::new(error.code, error.message, "pg_splade");
+    if let Some(detail) = error.detail {
+        report = report.set_detail(detail);
+    }
+    if let Some(hint) = error.hint {
+        report = report.set_hint(hint);
+    }
+    report
+}
@@ -0,0 +1,53 @@
+use std::hash::{DefaultHasher, Hash, Hasher};
+
+use pgrx::{pg_shmem_init, pg_sys, PGRXSharedMemory, PgLwLock};
+
+/// Models are hashed into a fixed number of slots. Two models sharing a slot
+/// only causes a needless reload when either of them changes.
+const SLOTS: usize = 1024;
+
+/// Generation of each model slot, bumped whenever a model is replaced or
+/// deleted so that every backend drops its loaded copy.
+#[derive(Clone, Copy)]
+struct Generations {
+    slots: [u64; SLOTS],
+}
+
+impl Default for Generations {
+    fn default() -> Self {
+        Self { slots: [0; SLOTS] }
+    }
+}
+
+unsafe impl PGRXSharedMemory for Generations {}
+
+static GENERATIONS: PgLwLock<Generations> = PgLwLock::new();
+
+pub fn init() {
+    pg_shmem_init!(GENERATIONS);
+}
+
+fn slot(name: &str) -> usize {
+    let mut hasher = DefaultHasher::new();
+    name.hash(&mut hasher);
+    (hasher.finish() % SLOTS as u64) as usize
+}
+
+/// Current generation of model `name`.
+pub fn current(name: &str) -> u64 {
+    // Models preloaded by the postmaster are loaded before shared memory
+    // exists, and are at the initial generation. Without shared memory,
+    // models are only invalidated in the session that changed them.
+    if !crate::preloaded() || !unsafe { pg_sys::IsUnderPostmaster } {
+        return 0;
+    }
+    GENERATIONS.share().slots[slot(name)]
+}
+
+/// Invalidates the copies of model `name` loaded in all backends.
+pub fn bump(name: &str) {
+    if !crate::preloaded() {
+        return;
+    }
+    GENERATIONS.exclusive().slots[slot(name)] += 1;
+}
@@ -1,19 +1,196 @@
-use std::ffi::CStr;
+use std::{ffi::CStr, time::Duration};
 
-use pgrx::{GucContext, GucFlags, GucRegistry, GucSetting};
+use pgrx::{GucContext, GucFlags, GucRegistry, GucSetting, PostgresGucEnum};
+
+#[allow(non_camel_case_types)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PostgresGucEnum)]
+pub enum ModelStorage {
+    filesystem,
+    database,
+}
+
+/// How `encode_query` turns a query into a sparse vector.
+#[allow(non_camel_case_types)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PostgresGucEnum)]
+pub enum QueryMode {
+    auto,
+    idf,
+    inference,
+}
 
 pub static PRELOAD_MODELS: GucSetting<Option<&CStr>> = GucSetting::<Option<&CStr>>::new(Some(c""));
 
+pub static DEFAULT_MODEL: GucSetting<Option<&CStr>> = GucSetting::<Option<&CStr>>::new(None);
+
+pub static MODEL_DIR: GucSetting<Option<&CStr>> =
+    GucSetting::<Option<&CStr>>::new(Some(c"$sharedir/splade"));
+
+pub static MODEL_SEARCH_PATH: GucSetting<Option<&CStr>> =
+    GucSetting::<Option<&CStr>>::new(Some(c"$sharedir/splade"));
+
+pub static MODEL_STORAGE: GucSetting<ModelStorage> =
+    GucSetting::<ModelStorage>::new(ModelStorage::filesystem);
+
+pub static QUERY_MODE: GucSetting<QueryMode> = GucSetting::<QueryMode>::new(QueryMode::auto);
+
+pub static LENIENT_LOADING: GucSetting<bool> = GucSetting::<bool>::new(false);
+
+pub static INFERENCE_WORKERS: GucSetting<i32> = GucSetting::<i32>::new(0);
+
+pub static CACHE_SIZE: GucSetting<i32> = GucSetting::<i32>::new(0);
+
+pub static BATCH_WAIT_MS: GucSetting<i32> = GucSetting::<i32>::new(0);
+
+pub static MAX_BATCH_SIZE: GucSetting<i32> = GucSetting::<i32>::new(32);
+
+pub static ALLOW_PICKLE_WEIGHTS: GucSetting<bool> = GucSetting::<bool>::new(false);
+
+pub static MAX_INPUT_BYTES: GucSetting<i32> = GucSetting::<i32>::new(1024 * 1024);
+
+pub static MAX_MODEL_MEMORY: GucSetting<i32> = GucSetting::<i32>::new(0);
+
 pub fn init() {
     GucRegistry::define_string_guc(
         "splade.preload_models",
-        "Preload models for Splade extension",
-        "Comma-separated list of models to preload. Available models: mini, distill.",
+        "Models to load at server start and when the configuration is reloaded",
+        "Comma-separated list of model names. It can also be set for each database with ALTER DATABASE.",
         &PRELOAD_MODELS,
+        GucContext::Suset,
+        GucFlags::default(),
+    );
+
+    GucRegistry::define_string_guc(
+        "splade.default_model",
+        "Model used by encode_document and encode_query when none is given",
+        "A model name or an alias created by create_model_alias.",
+        &DEFAULT_MODEL,
+        GucContext::Userset,
+        GucFlags::default(),
+    );
+
+    GucRegistry::define_enum_guc(
+        "splade.model_storage",
+        "Where downloaded and imported models are stored",
+        "filesystem: in the splade directory of the server. database: in the extension tables, so that they are replicated to standbys.",
+        &MODEL_STORAGE,
+        GucContext::Suset,
+        GucFlags::default(),
+    );
+
+    GucRegistry::define_enum_guc(
+        "splade.query_mode",
+        "How queries are encoded",
+        "idf: weight query tokens by the idf.json of the model (inference-free). inference: encode queries with the model like documents. auto: idf if the model has an idf.json, inference otherwise.",
+        &QUERY_MODE,
         GucContext::Userset,
         GucFlags::default(),
     );
 
+    GucRegistry::define_bool_guc(
+        "splade.lenient_loading",
+        "Skip inconsistent entries of model files instead of failing to load the model",
+        "When enabled, idf.json entries for tokens missing from the vocabulary are skipped with a WARNING.",
+        &LENIENT_LOADING,
+        GucContext::Userset,
+        GucFlags::default(),
+    );
+
+    GucRegistry::define_bool_guc(
+        "splade.allow_pickle_weights",
+        "Allow models that only ship pickled PyTorch weights",
+        "When enabled, download_model accepts repos that only provide pytorch_model.bin and converts it to model.safetensors. Only enable this for trusted repos.",
+        &ALLOW_PICKLE_WEIGHTS,
+        GucContext::Suset,
+        GucFlags::default(),
+    );
+
+    GucRegistry::define_int_guc(
+        "splade.max_input_bytes",
+        "Maximum size of a text to encode",
+        "Longer texts are rejected before tokenization. Models only read their first tokens, so most of a longer text is ignored anyway. 0 disables the limit.",
+        &MAX_INPUT_BYTES,
+        0,
+        i32::MAX,
+        GucContext::Suset,
+        GucFlags::UNIT_BYTE,
+    );
+
+    GucRegistry::define_int_guc(
+        "splade.max_model_memory",
+        "Maximum memory used by the models loaded in each process",
+        "Loading a model evicts the least recently used models of the process until it fits. The memory of a model is estimated as the size of its files. 0 disables the limit.",
+        &MAX_MODEL_MEMORY,
+        0,
+        i32::MAX,
+        GucContext::Suset,
+        GucFlags::UNIT_MB,
+    );
+
+    GucRegistry::define_int_guc(
+        "splade.batch_wait_ms",
+        "Time an inference worker waits for more requests to encode them together",
+        "Requests that arrive at the same worker within this time are encoded as one batch.",
+        &BATCH_WAIT_MS,
+        0,
+        1000,
+        GucContext::Sighup,
+        GucFlags::UNIT_MS,
+    );
+
+    GucRegistry::define_int_guc(
+        "splade.max_batch_size",
+        "Maximum number of requests an inference worker encodes together",
+        "Larger batches raise throughput, but use more memory for padded inputs.",
+        &MAX_BATCH_SIZE,
+        1,
+        1024,
+        GucContext::Sighup,
+        GucFlags::default(),
+    );
+
+    // Postmaster settings can only be defined while preloading.
+    if crate::preloaded() {
+        GucRegistry::define_int_guc(
+            "splade.inference_workers",
+            "Number of background workers that run model inference",
+            "If greater than 0, encode_document and encode_query send texts to these workers instead of loading models in each session.",
+            &INFERENCE_WORKERS,
+            0,
+            crate::worker::MAX_WORKERS as i32,
+            GucContext::Postmaster,
+            GucFlags::default(),
+        );
+
+        GucRegistry::define_int_guc(
+            "splade.cache_size",
+            "Shared memory for texts encoded by all sessions",
+            "encode_document and encode_query return the vectors of texts encoded before from this cache. 0 disables the cache.",
+            &CACHE_SIZE,
+            0,
+            i32::MAX,
+            GucContext::Postmaster,
+            GucFlags::UNIT_MB,
+        );
+
+        GucRegistry::define_string_guc(
+            "splade.model_dir",
+            "Directory models are downloaded into",
+            "download_model, import_model and delete_model manage models in this directory. $sharedir is replaced by the share directory of the server.",
+            &MODEL_DIR,
+            GucContext::Postmaster,
+            GucFlags::SUPERUSER_ONLY,
+        );
+
+        GucRegistry::define_string_guc(
+            "splade.model_search_path",
+            "Directories models are also loaded from",
+            "Comma-separated list of directories searched after splade.model_dir, which may be read-only. $sharedir is replaced by the share directory of the server.",
+            &MODEL_SEARCH_PATH,
+            GucContext::Postmaster,
+            GucFlags::SUPERUSER_ONLY,
+        );
+    }
+
     unsafe {
         #[cfg(any(feature = "pg13", feature = "pg14"))]
         pgrx::pg_sys::EmitWarningsOnPlaceholders(c"splade".as_ptr());
@@ -34,3 +211,71 @@ pub fn preload_models() -> Vec<String> {
         .map(|s| s.trim().to_string())
         .collect()
 }
+
+pub fn default_model() -> Option<String> {
+    DEFAULT_MODEL
+        .get()
+        .map(|model| model.to_string_lossy().trim().to_string())
+        .filter(|model| !model.is_empty())
+}
+
+pub fn model_dir() -> String {
+    MODEL_DIR
+        .get()
+        .map(|dir| dir.to_string_lossy().into_owned())
+        .filter(|dir| !dir.is_empty())
+        .unwrap_or_else(|| "$sharedir/splade".to_string())
+}
+
+pub fn model_search_path() -> Vec<String> {
+    let path = MODEL_SEARCH_PATH.get().unwrap_or_default();
+    path.to_string_lossy()
+        .split(',')
+        .map(|dir| dir.trim().to_string())
+        .filter(|dir| !dir.is_empty())
+        .collect()
+}
+
+pub fn model_storage() -> ModelStorage {
+    MODEL_STORAGE.get()
+}
+
+pub fn query_mode() -> QueryMode {
+    QUERY_MODE.get()
+}
+
+pub fn lenient_loading() -> bool {
+    LENIENT_LOADING.get()
+}
+
+pub fn inference_workers() -> usize {
+    INFERENCE_WORKERS.get() as usize
+}
+
+/// Size of the encoding cache in bytes.
+pub fn cache_size() -> usize {
+    CACHE_SIZE.get() as usize * 1024 * 1024
+}
+
+pub fn batch_wait() -> Duration {
+    Duration::from_millis(BATCH_WAIT_MS.get() as u64)
+}
+
+pub fn max_batch_size() -> usize {
+    MAX_BATCH_SIZE.get() as usize
+}
+
+/// Maximum size of a text to encode, `None` if unlimited.
+pub fn max_input_bytes() -> Option<usize> {
+    Some(MAX_INPUT_BYTES.get() as usize).filter(|&max| max > 0)
+}
+
+/// Maximum memory of the models loaded in a process in bytes, `None` if
+/// unlimited.
+pub fn max_model_memory() -> Option<u64> {
+    Some(MAX_MODEL_MEMORY.get() as u64 * 1024 * 1024).filter(|&max| max > 0)
+}
+
+pub fn allow_pickle_weights() -> bool {
+    ALLOW_PICKLE_WEIGHTS.get()
+}
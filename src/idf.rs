@@ -0,0 +1,142 @@
+use std::{collections::HashMap, ffi::CStr, path::Path};
+
+use anyhow::{anyhow, bail, Error, Result};
+use pgrx::{pg_sys, pg_sys::panic::ErrorReport, spi, PgMemoryContexts, PgRelation, Spi};
+
+use crate::{
+    error::{model_not_found, SqlError},
+    storage,
+    tokenizer::load_tokenizer,
+};
+
+const FETCH_SIZE: i64 = 1000;
+
+/// Creates model `target_model` as a copy of `model` whose `idf.json` is
+/// computed from the documents in column `text_column` of `source`. The
+/// column is declared as `name`, like column names in the catalogs, which is
+/// passed as a C string.
+#[pgrx::pg_extern(
+    volatile,
+    strict,
+    sql = r#"
+CREATE FUNCTION splade_build_idf(model text, source regclass, text_column name, target_model text)
+RETURNS void VOLATILE STRICT LANGUAGE c AS 'MODULE_PATHNAME', 'splade_build_idf_wrapper';
+"#
+)]
+fn splade_build_idf(
+    model: &str,
+    source: PgRelation,
+    text_column: &CStr,
+    target_model: &str,
+) -> Result<(), ErrorReport> {
+    crate::error::sql(|| {
+        let text_column = text_column.to_str()?;
+        storage::check_model_name(target_model)?;
+        if crate::job::is_active(target_model) {
+            bail!(SqlError::object_in_use(format!(
+                "Model {} is already being downloaded",
+                target_model
+            )));
+        }
+        let staging = storage::staging_dir(target_model)?;
+        crate::encode::check_model_absent(target_model)?;
+        let Some(path) = storage::locate(model)? else {
+            bail!(model_not_found(model));
+        };
+
+        let query = format!(
+            "SELECT {}::text FROM {}",
+            spi::quote_identifier(text_column),
+            spi::quote_qualified_identifier(source.namespace(), source.name())
+        );
+        let idf = compute_idf(&path, &query)?;
+
+        if staging.exists() {
+            std::fs::remove_dir_all(&staging)?;
+        }
+        std::fs::create_dir_all(&staging)?;
+        for entry in std::fs::read_dir(&path)? {
+            let entry = entry?;
+            let file = entry.file_name();
+            let Some(file) = file.to_str().filter(|f| !f.starts_with('.')) else {
+                continue;
+            };
+            if file == "idf.json" || !entry.file_type()?.is_file() {
+                continue;
+            }
+            // Copied rather than linked, as `import_model` can append to the
+            // files of either model in place.
+            std::fs::copy(entry.path(), staging.join(file))?;
+        }
+        std::fs::write(staging.join("idf.json"), serde_json::to_vec(&idf)?)?;
+        storage::install(target_model, &staging, crate::guc::model_storage())?;
+        crate::encode::invalidate(target_model);
+        Ok(())
+    })
+}
+
+/// Tokenizes the documents returned by `query` with the tokenizer of the model
+/// in `path`, and returns the BM25 IDF of every token of the vocabulary.
+fn compute_idf(path: &Path, query: &str) -> Result<HashMap<String, f32>> {
+    let mut tokenizer = load_tokenizer(path)?;
+    // Count every token of long documents, not only the ones the model sees.
+    tokenizer.with_truncation(None).map_err(Error::msg)?;
+
+    let mut df = vec![0u64; tokenizer.get_vocab_size(true)];
+    let mut seen = vec![false; df.len()];
+    let mut documents = 0u64;
+    let mut memcx = PgMemoryContexts::new("pg_splade idf");
+    Spi::connect(|client| {
+        let mut cursor = client.open_cursor(query, &[]);
+        loop {
+            let table = cursor.fetch(FETCH_SIZE)?;
+            if table.is_empty() {
+                break;
+            }
+            unsafe {
+                memcx.switch_to(|_| {
+                    for row in table {
+                        let Some(document) = row.get::<&str>(1)? else {
+                            continue;
+                        };
+                        let encoding =
+                            tokenizer.encode_fast(document, false).map_err(Error::msg)?;
+                        for &id in encoding.get_ids() {
+                            let id = id as usize;
+                            if !seen[id] {
+                                seen[id] = true;
+                                df[id] += 1;
+                            }
+                        }
+                        for &id in encoding.get_ids() {
+                            seen[id as usize] = false;
+                        }
+                        documents += 1;
+                    }
+                    Ok::<_, Error>(())
+                })?;
+                memcx.reset();
+                pg_sys::SPI_freetuptable(pg_sys::SPI_tuptable);
+            }
+            pgrx::check_for_interrupts!();
+        }
+        Ok::<_, Error>(())
+    })?;
+    if documents == 0 {
+        return Err(anyhow!("No documents found to compute IDF from"));
+    }
+
+    let added_vocabulary = tokenizer.get_added_vocabulary();
+    let n = documents as f32;
+    let idf = tokenizer
+        .get_vocab(true)
+        .into_iter()
+        .filter(|(token, _)| !added_vocabulary.is_special_token(token))
+        .map(|(token, id)| {
+            let df = df[id as usize] as f32;
+            let idf = (1.0 + (n - df + 0.5) / (df + 0.5)).ln();
+            (token, idf)
+        })
+        .collect();
+    Ok(idf)
+}
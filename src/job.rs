@@ -0,0 +1,405 @@
+use std::{
+    panic::AssertUnwindSafe,
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, bail, Result};
+use pgrx::{
+    bgworkers::{BackgroundWorker, BackgroundWorkerBuilder, BgWorkerStartTime, SignalWakeFlags},
+    datum::TimestampWithTimeZone,
+    iter::TableIterator,
+    name, pg_guard, pg_shmem_init, pg_sys,
+    pg_sys::panic::ErrorReport,
+    PGRXSharedMemory, PgLwLock, PgSqlErrorCode,
+};
+
+use crate::{
+    download::{DownloadOptions, Progress},
+    error::SqlError,
+    guc::ModelStorage,
+};
+
+const MAX_JOBS: usize = 16;
+const NAME_LEN: usize = 64;
+const REPO_ID_LEN: usize = 128;
+const ERROR_LEN: usize = 256;
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(500);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum JobState {
+    Free,
+    Pending,
+    Running,
+    Succeeded,
+    Failed,
+}
+
+impl JobState {
+    fn as_str(&self) -> &'static str {
+        match self {
+            JobState::Free => "free",
+            JobState::Pending => "pending",
+            JobState::Running => "running",
+            JobState::Succeeded => "succeeded",
+            JobState::Failed => "failed",
+        }
+    }
+
+    fn is_active(&self) -> bool {
+        matches!(self, JobState::Pending | JobState::Running)
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Job {
+    id: i64,
+    state: JobState,
+    name: [u8; NAME_LEN],
+    repo_id: [u8; REPO_ID_LEN],
+    file: [u8; NAME_LEN],
+    error: [u8; ERROR_LEN],
+    options: DownloadOptions,
+    /// Database to store the model in, for `ModelStorage::database`.
+    database: [u8; NAME_LEN],
+    /// Process id of the worker, once it started.
+    pid: Option<i32>,
+    downloaded: u64,
+    total: Option<u64>,
+    created_at: pg_sys::TimestampTz,
+    started_at: Option<pg_sys::TimestampTz>,
+    finished_at: Option<pg_sys::TimestampTz>,
+}
+
+impl Default for Job {
+    fn default() -> Self {
+        Self {
+            id: 0,
+            state: JobState::Free,
+            name: [0; NAME_LEN],
+            repo_id: [0; REPO_ID_LEN],
+            file: [0; NAME_LEN],
+            error: [0; ERROR_LEN],
+            options: DownloadOptions {
+                storage: ModelStorage::filesystem,
+                allow_pickle_weights: false,
+            },
+            database: [0; NAME_LEN],
+            pid: None,
+            downloaded: 0,
+            total: None,
+            created_at: 0,
+            started_at: None,
+            finished_at: None,
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Jobs {
+    next_id: i64,
+    jobs: [Job; MAX_JOBS],
+}
+
+impl Default for Jobs {
+    fn default() -> Self {
+        Self {
+            next_id: 1,
+            jobs: [Job::default(); MAX_JOBS],
+        }
+    }
+}
+
+unsafe impl PGRXSharedMemory for Jobs {}
+
+static JOBS: PgLwLock<Jobs> = PgLwLock::new();
+
+pub fn init() {
+    pg_shmem_init!(JOBS);
+}
+
+pub(crate) fn write_str(buf: &mut [u8], s: &str) {
+    let mut len = s.len().min(buf.len() - 1);
+    while !s.is_char_boundary(len) {
+        len -= 1;
+    }
+    buf.fill(0);
+    buf[..len].copy_from_slice(&s.as_bytes()[..len]);
+}
+
+pub(crate) fn read_str(buf: &[u8]) -> String {
+    let len = buf.iter().position(|&b| b == 0).unwrap_or(buf.len());
+    String::from_utf8_lossy(&buf[..len]).into_owned()
+}
+
+fn now() -> pg_sys::TimestampTz {
+    unsafe { pg_sys::GetCurrentTimestamp() }
+}
+
+/// Whether a background download of model `name` is pending or running.
+pub fn is_active(name: &str) -> bool {
+    if !crate::preloaded() {
+        return false;
+    }
+    let jobs = JOBS.share();
+    jobs.jobs
+        .iter()
+        .any(|job| job.state.is_active() && read_str(&job.name) == name)
+}
+
+fn finish(id: i64, result: Result<()>) {
+    let mut jobs = JOBS.exclusive();
+    if let Some(job) = jobs
+        .jobs
+        .iter_mut()
+        .find(|job| job.id == id && job.state.is_active())
+    {
+        match result {
+            Ok(()) => job.state = JobState::Succeeded,
+            Err(e) => {
+                job.state = JobState::Failed;
+                write_str(&mut job.error, &e.to_string());
+            }
+        }
+        job.finished_at = Some(now());
+    }
+}
+
+#[pgrx::pg_extern(volatile, strict)]
+fn download_model_async(name: &str, repo_id: &str) -> Result<i64, ErrorReport> {
+    crate::error::sql(|| {
+        if !crate::preloaded() {
+            bail!(SqlError::new(
+                PgSqlErrorCode::ERRCODE_OBJECT_NOT_IN_PREREQUISITE_STATE,
+                "download_model_async requires pg_splade in shared_preload_libraries"
+            )
+            .with_hint("Use download_model instead"));
+        }
+        crate::storage::check_model_name(name)?;
+        if repo_id.len() >= REPO_ID_LEN {
+            bail!(SqlError::program_limit_exceeded(format!(
+                "Repo id {} is too long",
+                repo_id
+            )));
+        }
+        crate::encode::check_model_absent(name)?;
+        let database = unsafe {
+            let database = pg_sys::get_database_name(pg_sys::MyDatabaseId);
+            std::ffi::CStr::from_ptr(database)
+                .to_string_lossy()
+                .into_owned()
+        };
+
+        let id = {
+            let mut jobs = JOBS.exclusive();
+            if jobs
+                .jobs
+                .iter()
+                .any(|job| job.state.is_active() && read_str(&job.name) == name)
+            {
+                bail!(SqlError::object_in_use(format!(
+                    "Model {} is already being downloaded",
+                    name
+                )));
+            }
+            // Reuse a free slot, or else the slot of the oldest finished job.
+            let slot = jobs
+                .jobs
+                .iter()
+                .enumerate()
+                .filter(|(_, job)| !job.state.is_active())
+                .min_by_key(|(_, job)| (job.state != JobState::Free, job.id))
+                .map(|(i, _)| i)
+                .ok_or(SqlError::program_limit_exceeded(format!(
+                    "Too many download jobs are running, at most {} are allowed",
+                    MAX_JOBS
+                )))?;
+            let id = jobs.next_id;
+            jobs.next_id += 1;
+            let job = &mut jobs.jobs[slot];
+            *job = Job {
+                id,
+                state: JobState::Pending,
+                options: DownloadOptions::from_gucs(),
+                created_at: now(),
+                ..Job::default()
+            };
+            write_str(&mut job.name, name);
+            write_str(&mut job.repo_id, repo_id);
+            write_str(&mut job.database, &database);
+            id
+        };
+
+        let worker = BackgroundWorkerBuilder::new("pg_splade download")
+            .set_library("pg_splade")
+            .set_function("splade_download_worker")
+            .set_argument(Some(pg_sys::Datum::from(id)))
+            .enable_spi_access()
+            .set_start_time(BgWorkerStartTime::ConsistentState)
+            .set_notify_pid(unsafe { pg_sys::MyProcPid })
+            .load_dynamic();
+        let Ok(worker) = worker else {
+            const MSG: &str =
+                "Could not start background worker, consider increasing max_worker_processes";
+            finish(id, Err(anyhow!(MSG)));
+            bail!(MSG);
+        };
+        // The postmaster can fail to fork the worker. A worker that already
+        // finished its job is stopped too, in which case this does nothing.
+        if let Err(status) = worker.wait_for_startup() {
+            finish(
+                id,
+                Err(anyhow!("Background worker did not start: {:?}", status)),
+            );
+        }
+        Ok(id)
+    })
+}
+
+struct JobProgress {
+    id: i64,
+    last_update: Instant,
+}
+
+impl Progress for JobProgress {
+    fn update(&mut self, file: &str, downloaded: u64, total: Option<u64>) -> Result<()> {
+        if BackgroundWorker::sigterm_received() {
+            bail!("Download was terminated");
+        }
+        if self.last_update.elapsed() >= PROGRESS_INTERVAL || Some(downloaded) == total {
+            let mut jobs = JOBS.exclusive();
+            if let Some(job) = jobs.jobs.iter_mut().find(|job| job.id == self.id) {
+                write_str(&mut job.file, file);
+                job.downloaded = downloaded;
+                job.total = total;
+            }
+            self.last_update = Instant::now();
+        }
+        Ok(())
+    }
+}
+
+/// Fails the job in `arg` if its worker exits without finishing it, for
+/// example on a `FATAL` error while connecting to a dropped database.
+#[pg_guard]
+unsafe extern "C" fn worker_exit(_code: std::ffi::c_int, arg: pg_sys::Datum) {
+    finish(
+        arg.value() as i64,
+        Err(anyhow!("Download worker exited unexpectedly")),
+    );
+}
+
+#[pg_guard]
+#[no_mangle]
+pub extern "C" fn splade_download_worker(arg: pg_sys::Datum) {
+    BackgroundWorker::attach_signal_handlers(SignalWakeFlags::SIGTERM);
+
+    let id = arg.value() as i64;
+    unsafe { pg_sys::before_shmem_exit(Some(worker_exit), arg) };
+    let (name, repo_id, options, database) = {
+        let mut jobs = JOBS.exclusive();
+        let Some(job) = jobs.jobs.iter_mut().find(|job| job.id == id) else {
+            return;
+        };
+        job.state = JobState::Running;
+        job.pid = Some(unsafe { pg_sys::MyProcPid });
+        job.started_at = Some(now());
+        (
+            read_str(&job.name),
+            read_str(&job.repo_id),
+            job.options,
+            read_str(&job.database),
+        )
+    };
+
+    let mut progress = JobProgress {
+        id,
+        last_update: Instant::now(),
+    };
+    // Connected for the filesystem too, as staging directories are locked
+    // with the lock manager.
+    BackgroundWorker::connect_worker_to_spi(Some(&database), None);
+    let result = std::panic::catch_unwind(AssertUnwindSafe(|| match options.storage {
+        ModelStorage::filesystem => {
+            // Models stored in the database and aliases are only visible in a
+            // transaction, which the download itself runs outside of.
+            BackgroundWorker::transaction(|| crate::encode::check_model_absent(&name))
+                .and_then(|()| crate::encode::download(&name, &repo_id, options, &mut progress))
+        }
+        ModelStorage::database => {
+            BackgroundWorker::transaction(AssertUnwindSafe(|| {
+                if let Err(e) = crate::encode::download(&name, &repo_id, options, &mut progress) {
+                    // Raise an error to roll back a partially stored model.
+                    finish(id, Err(e));
+                    pgrx::error!("Download of model {} failed", name);
+                }
+                Ok(())
+            }))
+        }
+    }));
+    match result {
+        Ok(result) => finish(id, result),
+        Err(payload) => {
+            finish(id, Err(anyhow!("Download worker exited unexpectedly")));
+            std::panic::resume_unwind(payload);
+        }
+    }
+}
+
+#[allow(clippy::type_complexity)]
+#[pgrx::pg_extern(volatile)]
+fn splade_download_jobs_internal() -> TableIterator<
+    'static,
+    (
+        name!(id, i64),
+        name!(name, String),
+        name!(repo_id, String),
+        name!(state, String),
+        name!(pid, Option<i32>),
+        name!(file, Option<String>),
+        name!(bytes_downloaded, i64),
+        name!(bytes_total, Option<i64>),
+        name!(error, Option<String>),
+        name!(created_at, Option<TimestampWithTimeZone>),
+        name!(started_at, Option<TimestampWithTimeZone>),
+        name!(finished_at, Option<TimestampWithTimeZone>),
+    ),
+> {
+    let timestamp = |ts: Option<pg_sys::TimestampTz>| -> Option<TimestampWithTimeZone> {
+        ts.and_then(|ts| TimestampWithTimeZone::try_from(ts).ok())
+    };
+    if !crate::preloaded() {
+        return TableIterator::new(vec![]);
+    }
+    let jobs = JOBS.share();
+    let mut rows = jobs
+        .jobs
+        .iter()
+        .filter(|job| job.state != JobState::Free)
+        .map(|job| {
+            (
+                job.id,
+                read_str(&job.name),
+                read_str(&job.repo_id),
+                job.state.as_str().to_string(),
+                job.pid.filter(|_| job.state.is_active()),
+                Some(read_str(&job.file)).filter(|f| !f.is_empty()),
+                job.downloaded as i64,
+                job.total.map(|total| total as i64),
+                Some(read_str(&job.error)).filter(|e| !e.is_empty()),
+                timestamp(Some(job.created_at)),
+                timestamp(job.started_at),
+                timestamp(job.finished_at),
+            )
+        })
+        .collect::<Vec<_>>();
+    rows.sort_by_key(|row| row.0);
+    TableIterator::new(rows)
+}
+
+pgrx::extension_sql!(
+    r#"
+CREATE VIEW splade_download_jobs AS SELECT * FROM splade_download_jobs_internal();
+"#,
+    name = "splade_download_jobs",
+    requires = [splade_download_jobs_internal]
+);
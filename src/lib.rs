@@ -1,9 +1,25 @@
 ::pgrx::pg_module_magic!();
 
+use std::sync::atomic::{AtomicBool, Ordering};
+
+pub mod alias;
+pub mod cache;
 pub mod datatype;
+pub mod download;
 pub mod encode;
+pub mod error;
+pub mod generation;
 pub mod guc;
+pub mod idf;
+pub mod job;
 pub mod model;
+pub mod preload;
+pub mod remote;
+pub mod stats;
+pub mod storage;
+pub mod tokenizer;
+pub mod transformer;
+pub mod worker;
 
 #[cfg(not(all(target_endian = "little", target_pointer_width = "64")))]
 compile_error!("Target is not supported.");
@@ -11,14 +27,75 @@ compile_error!("Target is not supported.");
 #[cfg(not(any(feature = "pg14", feature = "pg15", feature = "pg16", feature = "pg17")))]
 compiler_error!("PostgreSQL version must be selected.");
 
+// Model management writes to the server's filesystem, so it is restricted to
+// members of `splade_admin`, while encoding stays available to everyone.
+pgrx::extension_sql!(
+    r#"
+DO $$
+BEGIN
+    IF NOT EXISTS (SELECT 1 FROM pg_catalog.pg_roles WHERE rolname = 'splade_admin') THEN
+        CREATE ROLE splade_admin NOLOGIN;
+    END IF;
+END
+$$;
+REVOKE ALL ON FUNCTION
+    download_model(text, text),
+    download_model_async(text, text),
+    delete_model(text),
+    reload_model(text),
+    import_model(text, text, bytea),
+    splade_build_idf(text, regclass, name, text),
+    create_remote_model(text, json),
+    create_model_alias(text, text),
+    drop_model_alias(text),
+    pg_stat_splade_reset()
+FROM PUBLIC;
+GRANT EXECUTE ON FUNCTION
+    download_model(text, text),
+    download_model_async(text, text),
+    delete_model(text),
+    reload_model(text),
+    import_model(text, text, bytea),
+    splade_build_idf(text, regclass, name, text),
+    create_remote_model(text, json),
+    create_model_alias(text, text),
+    drop_model_alias(text),
+    pg_stat_splade_reset()
+TO splade_admin;
+-- splade_model_files holds the headers of remote models, such as API keys.
+GRANT SELECT ON splade_models, splade_model_aliases TO PUBLIC;
+GRANT SELECT, INSERT, UPDATE, DELETE ON splade_models, splade_model_files, splade_model_aliases TO splade_admin;
+"#,
+    name = "privileges",
+    finalize
+);
+
+/// Whether pg_splade was loaded by `shared_preload_libraries`, so that shared
+/// memory and background workers are available.
+static PRELOADED: AtomicBool = AtomicBool::new(false);
+
+pub fn preloaded() -> bool {
+    PRELOADED.load(Ordering::Relaxed)
+}
+
 #[pgrx::pg_guard]
 unsafe extern "C" fn _PG_init() {
-    if unsafe { pgrx::pg_sys::IsUnderPostmaster } {
-        pgrx::error!("pg_splade must be loaded in the postmaster process");
+    // Sessions can also load the library on first use, without the features
+    // that need the postmaster.
+    if unsafe { pgrx::pg_sys::process_shared_preload_libraries_in_progress } {
+        PRELOADED.store(true, Ordering::Relaxed);
     }
 
     guc::init();
-    encode::init();
+    if preloaded() {
+        encode::init();
+        job::init();
+        generation::init();
+        worker::init();
+        preload::init();
+        stats::init();
+        cache::init();
+    }
 }
 
 #[cfg(test)]
@@ -2,6 +2,7 @@ use std::{
     collections::HashMap,
     path::{Path, PathBuf},
     sync::Arc,
+    time::{Duration, Instant},
 };
 
 use anyhow::{anyhow, Error, Result};
@@ -11,42 +12,100 @@ use candle_core::{
 };
 use candle_nn::VarBuilder;
 use candle_transformers::models::{bert::BertForMaskedLM, distilbert::DistilBertForMaskedLM};
-use tokenizers::{PaddingParams, Tokenizer};
+use tokenizers::Tokenizer;
+
+use crate::{
+    error::SqlError,
+    guc::QueryMode,
+    remote::{self, RemoteModel},
+    tokenizer::load_tokenizer,
+    transformer::{Bert, DistilBert},
+};
+
+/// A text encoded by a model.
+pub struct Encoded {
+    pub vector: Tensor,
+    /// Number of tokens the model saw, 0 if unknown.
+    pub tokens: usize,
+    /// Whether the text was cut to the maximum length of the model.
+    pub truncated: bool,
+}
+
+/// Time spent in each stage of encoding a text, for `splade_profile`.
+pub struct Profile {
+    last: Instant,
+    pub stages: Vec<(&'static str, Duration)>,
+}
+
+impl Profile {
+    pub fn new() -> Self {
+        Self {
+            last: Instant::now(),
+            stages: vec![],
+        }
+    }
+
+    /// Records the time since the previous stage finished as `stage`.
+    pub fn finish(&mut self, stage: &'static str) {
+        let now = Instant::now();
+        self.stages.push((stage, now - self.last));
+        self.last = now;
+    }
+}
+
+impl Default for Profile {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 
 pub trait Encode {
-    fn encode_document(&self, document: &str) -> Result<Tensor>;
-    fn encode_query(&self, query: &str) -> Result<Tensor>;
+    fn encode_document(&self, document: &str) -> Result<Encoded>;
+    /// Same as `encode_document`, recording the time of each stage in
+    /// `profile`.
+    fn profile_document(&self, document: &str, profile: &mut Profile) -> Result<Encoded>;
+    fn encode_query(&self, query: &str, mode: QueryMode) -> Result<Encoded>;
+    fn encode_documents(&self, documents: &[&str]) -> Result<Vec<Encoded>>;
+    fn encode_queries(&self, queries: &[&str], mode: QueryMode) -> Result<Vec<Encoded>>;
 }
 
 impl<T: MaskedLM> Encode for SpladeModel<T> {
-    fn encode_document(&self, document: &str) -> Result<Tensor> {
+    fn encode_document(&self, document: &str) -> Result<Encoded> {
         self.encode_document(document)
     }
 
-    fn encode_query(&self, query: &str) -> Result<Tensor> {
-        self.encode_query(query)
+    fn profile_document(&self, document: &str, profile: &mut Profile) -> Result<Encoded> {
+        let mut vectors = self.encode_documents_with(&[document], Some(profile))?;
+        Ok(vectors.remove(0))
+    }
+
+    fn encode_query(&self, query: &str, mode: QueryMode) -> Result<Encoded> {
+        self.encode_query(query, mode)
+    }
+
+    fn encode_documents(&self, documents: &[&str]) -> Result<Vec<Encoded>> {
+        self.encode_documents(documents)
+    }
+
+    fn encode_queries(&self, queries: &[&str], mode: QueryMode) -> Result<Vec<Encoded>> {
+        self.encode_queries(queries, mode)
     }
 }
 
 pub type ModelPtr = Arc<dyn Encode + Send + Sync>;
 
 pub fn load_dynamic_model(path: &Path) -> Result<ModelPtr> {
-    let config = std::fs::read_to_string(path.join("config.json"))?;
-    let config: serde_json::Value = serde_json::from_str(&config)?;
-    // get config['architectures'][0]
-    let architecture = config
-        .get("architectures")
-        .and_then(|v| v.as_array())
-        .and_then(|v| v.first())
-        .and_then(|v| v.as_str())
-        .ok_or(anyhow!("Failed to get architecture"))?;
-    let model = match architecture {
+    if path.join(remote::CONFIG_FILE).exists() {
+        return Ok(Arc::new(RemoteModel::load(path)?));
+    }
+    let architecture = architecture(path)?;
+    let model = match architecture.as_str() {
         "BertForMaskedLM" => {
-            let model = SpladeModel::<BertForMaskedLM>::load(path)?;
+            let model = SpladeModel::<Bert>::load(path)?;
             Arc::new(model) as ModelPtr
         }
         "DistilBertForMaskedLM" => {
-            let model = SpladeModel::<DistilBertForMaskedLM>::load(path)?;
+            let model = SpladeModel::<DistilBert>::load(path)?;
             Arc::new(model) as ModelPtr
         }
         _ => return Err(anyhow!("Unknown architecture: {}", architecture)),
@@ -54,6 +113,25 @@ pub fn load_dynamic_model(path: &Path) -> Result<ModelPtr> {
     Ok(model)
 }
 
+/// Reads and parses the JSON file `file` of the model in `path`.
+pub(crate) fn read_json<T: for<'de> serde::Deserialize<'de>>(path: &Path, file: &str) -> Result<T> {
+    let content =
+        std::fs::read(path.join(file)).map_err(|e| anyhow!("Failed to read {}: {}", file, e))?;
+    serde_json::from_slice(&content).map_err(|e| anyhow!("Failed to parse {}: {}", file, e))
+}
+
+fn architecture(path: &Path) -> Result<String> {
+    let config: serde_json::Value = read_json(path, "config.json")?;
+    // get config['architectures'][0]
+    config
+        .get("architectures")
+        .and_then(|v| v.as_array())
+        .and_then(|v| v.first())
+        .and_then(|v| v.as_str())
+        .map(str::to_string)
+        .ok_or(anyhow!("Failed to get architectures from config.json"))
+}
+
 pub trait MaskedLM {
     type Config: for<'de> serde::Deserialize<'de>;
     const DTYPE: DType;
@@ -70,7 +148,8 @@ pub trait MaskedLM {
 pub struct SpladeModel<T> {
     model: T,
     tokenizer: Tokenizer,
-    idf: Tensor,
+    /// Only inference-free models ship an `idf.json`.
+    idf: Option<Tensor>,
     special_token_id_mask: Tensor,
     device: Device,
     vocab_size: usize,
@@ -81,26 +160,92 @@ impl<T: MaskedLM> SpladeModel<T> {
         load_model::<T>(path)
     }
 
-    pub fn encode_document(&self, document: &str) -> Result<Tensor> {
-        let feature = self
+    pub fn encode_document(&self, document: &str) -> Result<Encoded> {
+        let mut vectors = self.encode_documents(&[document])?;
+        Ok(vectors.remove(0))
+    }
+
+    /// Encodes `documents` in one forward pass, padding them to the longest.
+    pub fn encode_documents(&self, documents: &[&str]) -> Result<Vec<Encoded>> {
+        self.encode_documents_with(documents, None)
+    }
+
+    fn encode_documents_with(
+        &self,
+        documents: &[&str],
+        mut profile: Option<&mut Profile>,
+    ) -> Result<Vec<Encoded>> {
+        if documents.is_empty() {
+            return Ok(vec![]);
+        }
+        // Operations may run asynchronously on GPUs, so they are waited for
+        // to attribute their time to the right stage.
+        let mut finish = |stage: &'static str| -> Result<()> {
+            if let Some(profile) = profile.as_deref_mut() {
+                self.device.synchronize()?;
+                profile.finish(stage);
+            }
+            Ok(())
+        };
+        let features = self
             .tokenizer
-            .encode_fast(document, true)
+            .encode_batch_fast(documents.to_vec(), true)
             .map_err(Error::msg)?;
-        let input_ids = Tensor::new(feature.get_ids(), &self.device)?.unsqueeze(0)?;
-        let attention_mask =
-            Tensor::new(feature.get_attention_mask(), &self.device)?.unsqueeze(0)?;
+        finish("tokenize")?;
+        pgrx::check_for_interrupts!();
+        let shape = (features.len(), features[0].len());
+        let input_ids = features
+            .iter()
+            .flat_map(|feature| feature.get_ids().iter().copied())
+            .collect::<Vec<_>>();
+        let attention_mask = features
+            .iter()
+            .flat_map(|feature| feature.get_attention_mask().iter().copied())
+            .collect::<Vec<_>>();
+        let input_ids = Tensor::from_vec(input_ids, shape, &self.device)?;
+        let attention_mask = Tensor::from_vec(attention_mask, shape, &self.device)?;
+        finish("tensors")?;
         let ys = self.model.forward(&input_ids, &attention_mask)?;
+        finish("forward")?;
+        pgrx::check_for_interrupts!();
 
-        let vector = ys
+        let vectors = ys
             .broadcast_mul(&attention_mask.unsqueeze(2)?.to_dtype(T::DTYPE)?)?
             .max(1)?;
-        let vector = T::activation(&vector)?;
-        let vector = vector.broadcast_mul(&self.special_token_id_mask)?;
-        let vector = vector.squeeze(0)?;
-        Ok(vector)
+        let vectors = T::activation(&vectors)?;
+        let vectors = vectors.broadcast_mul(&self.special_token_id_mask)?;
+        finish("pooling")?;
+        features
+            .iter()
+            .enumerate()
+            .map(|(i, feature)| {
+                Ok(Encoded {
+                    vector: vectors.get(i)?,
+                    tokens: feature.get_attention_mask().iter().sum::<u32>() as usize,
+                    truncated: !feature.get_overflowing().is_empty(),
+                })
+            })
+            .collect::<Result<Vec<_>>>()
     }
 
-    pub fn encode_query(&self, query: &str) -> Result<Tensor> {
+    /// Returns the IDF weights to encode queries with in `mode`, or `None` if
+    /// queries are encoded like documents.
+    fn query_idf(&self, mode: QueryMode) -> Result<Option<&Tensor>> {
+        match (mode, &self.idf) {
+            (QueryMode::inference, _) | (QueryMode::auto, None) => Ok(None),
+            (_, Some(idf)) => Ok(Some(idf)),
+            (QueryMode::idf, None) => {
+                Err(SqlError::invalid_parameter_value("Model has no idf.json")
+                    .with_hint("Set splade.query_mode to inference or auto")
+                    .into())
+            }
+        }
+    }
+
+    pub fn encode_query(&self, query: &str, mode: QueryMode) -> Result<Encoded> {
+        let Some(idf) = self.query_idf(mode)? else {
+            return self.encode_document(query);
+        };
         let feature = self
             .tokenizer
             .encode_fast(query, true)
@@ -113,8 +258,24 @@ impl<T: MaskedLM> SpladeModel<T> {
         }
         let query_tensor = Tensor::from_vec(query_vector, self.vocab_size, &self.device)?;
 
-        let res = query_tensor.broadcast_mul(&self.idf)?;
-        Ok(res)
+        Ok(Encoded {
+            vector: query_tensor.broadcast_mul(idf)?,
+            tokens: input_ids.len(),
+            truncated: !feature.get_overflowing().is_empty(),
+        })
+    }
+
+    pub fn encode_queries(&self, queries: &[&str], mode: QueryMode) -> Result<Vec<Encoded>> {
+        if self.query_idf(mode)?.is_none() {
+            return self.encode_documents(queries);
+        }
+        queries
+            .iter()
+            .map(|query| {
+                pgrx::check_for_interrupts!();
+                self.encode_query(query, mode)
+            })
+            .collect()
     }
 }
 
@@ -124,52 +285,22 @@ struct LoadContext {
     dtype: DType,
 }
 
-fn load_model<T: MaskedLM>(path: &Path) -> Result<SpladeModel<T>> {
-    let ctx = LoadContext {
-        assets_path: path.to_path_buf(),
-        device: device()?,
-        dtype: T::DTYPE,
-    };
-
-    let config = std::fs::read_to_string(ctx.assets_path.join("config.json"))?;
-    let config: T::Config = serde_json::from_str(&config)?;
-    let mut tokenizer =
-        Tokenizer::from_file(ctx.assets_path.join("tokenizer.json")).map_err(Error::msg)?;
-    if let Some(pp) = tokenizer.get_padding_mut() {
-        pp.strategy = tokenizers::PaddingStrategy::BatchLongest;
-    } else {
-        tokenizer.with_padding(Some(PaddingParams {
-            strategy: tokenizers::PaddingStrategy::BatchLongest,
-            ..Default::default()
-        }));
-    }
-    if let Some(tr) = tokenizer.get_truncation_mut() {
-        tr.strategy = tokenizers::TruncationStrategy::LongestFirst;
-    } else {
-        tokenizer
-            .with_truncation(Some(tokenizers::TruncationParams {
-                strategy: tokenizers::TruncationStrategy::LongestFirst,
-                ..Default::default()
-            }))
-            .map_err(Error::msg)?;
+impl LoadContext {
+    fn new(path: &Path, dtype: DType) -> Result<Self> {
+        Ok(Self {
+            assets_path: path.to_path_buf(),
+            device: device()?,
+            dtype,
+        })
     }
+}
 
-    let vb = if ctx.assets_path.join("pytorch_model.bin").exists() {
-        VarBuilder::from_pth(
-            ctx.assets_path.join("pytorch_model.bin"),
-            ctx.dtype,
-            &ctx.device,
-        )?
-    } else {
-        unsafe {
-            VarBuilder::from_mmaped_safetensors(
-                &[ctx.assets_path.join("model.safetensors")],
-                ctx.dtype,
-                &ctx.device,
-            )
-        }?
-    };
-    let model = T::load(vb, &config)?;
+fn load_model<T: MaskedLM>(path: &Path) -> Result<SpladeModel<T>> {
+    let ctx = LoadContext::new(path, T::DTYPE)?;
+
+    let tokenizer =
+        load_tokenizer(&ctx.assets_path).map_err(|e| anyhow!("Failed to load tokenizer: {}", e))?;
+    let model = load_weights::<T>(&ctx)?;
     let idf = get_tokenizer_idf(&tokenizer, &ctx)?;
 
     let mut special_token_id_vec = vec![1.0f32; tokenizer.get_vocab_size(true) as usize];
@@ -196,6 +327,63 @@ fn load_model<T: MaskedLM>(path: &Path) -> Result<SpladeModel<T>> {
     })
 }
 
+fn load_weights<T: MaskedLM>(ctx: &LoadContext) -> Result<T> {
+    let config: T::Config = read_json(&ctx.assets_path, "config.json")?;
+    let vb = var_builder(ctx)?;
+    T::load(vb, &config).map_err(|e| anyhow!("Failed to load weights: {}", e))
+}
+
+fn var_builder(ctx: &LoadContext) -> Result<VarBuilder<'static>> {
+    let safetensors = ctx.assets_path.join("model.safetensors");
+    let index = ctx.assets_path.join("model.safetensors.index.json");
+    let pickle = ctx.assets_path.join("pytorch_model.bin");
+    let vb = if !safetensors.exists() && index.exists() {
+        let shards = safetensors_shards(&ctx.assets_path)?
+            .iter()
+            .map(|shard| ctx.assets_path.join(shard))
+            .collect::<Vec<_>>();
+        unsafe { VarBuilder::from_mmaped_safetensors(&shards, ctx.dtype, &ctx.device) }
+            .map_err(|e| anyhow!("Failed to load model.safetensors.index.json shards: {}", e))?
+    } else if safetensors.exists() || !pickle.exists() {
+        unsafe { VarBuilder::from_mmaped_safetensors(&[safetensors], ctx.dtype, &ctx.device) }
+            .map_err(|e| anyhow!("Failed to load model.safetensors: {}", e))?
+    } else if crate::guc::allow_pickle_weights() {
+        VarBuilder::from_pth(pickle, ctx.dtype, &ctx.device)
+            .map_err(|e| anyhow!("Failed to load pytorch_model.bin: {}", e))?
+    } else {
+        return Err(anyhow!(
+            "Model only has pickled weights pytorch_model.bin, convert them to model.safetensors or set splade.allow_pickle_weights"
+        ));
+    };
+    Ok(vb)
+}
+
+/// Returns the shard files listed in the `weight_map` of the sharded
+/// safetensors checkpoint index in `path`, sorted and without duplicates.
+pub fn safetensors_shards(path: &Path) -> Result<Vec<String>> {
+    let index: serde_json::Value = read_json(path, "model.safetensors.index.json")?;
+    let weight_map = index
+        .get("weight_map")
+        .and_then(|v| v.as_object())
+        .ok_or(anyhow!(
+            "Failed to get weight_map from model.safetensors.index.json"
+        ))?;
+    let mut shards = weight_map
+        .values()
+        .map(|v| {
+            v.as_str()
+                .map(str::to_string)
+                .ok_or(anyhow!("Invalid shard file name"))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    shards.sort();
+    shards.dedup();
+    for shard in &shards {
+        crate::storage::check_file_name(shard)?;
+    }
+    Ok(shards)
+}
+
 fn device() -> Result<Device> {
     let res = if cuda_is_available() {
         Device::new_cuda(0)?
@@ -207,60 +395,165 @@ fn device() -> Result<Device> {
     Ok(res)
 }
 
-fn get_tokenizer_idf(tokenizer: &Tokenizer, ctx: &LoadContext) -> Result<Tensor> {
-    let idf_content = std::fs::read(ctx.assets_path.join("idf.json"))?;
-    let idf: HashMap<String, f32> = serde_json::from_slice(&idf_content)?;
+fn get_tokenizer_idf(tokenizer: &Tokenizer, ctx: &LoadContext) -> Result<Option<Tensor>> {
+    if !ctx.assets_path.join("idf.json").exists() {
+        return Ok(None);
+    }
+    let idf: HashMap<String, f32> = read_json(&ctx.assets_path, "idf.json")?;
+    let (idf_vector, unknown) = idf_vector(tokenizer, &idf);
+    if !unknown.is_empty() {
+        let message = unknown_tokens_message(unknown, idf.len());
+        if !crate::guc::lenient_loading() {
+            return Err(anyhow!(
+                "{}, set splade.lenient_loading to skip them",
+                message
+            ));
+        }
+        pgrx::warning!("{}, skipping them", message);
+    }
+    let res = Tensor::from_vec(idf_vector, tokenizer.get_vocab_size(true), &ctx.device)?;
+    Ok(Some(res))
+}
 
-    let mut idf_tensor = vec![0.0; tokenizer.get_vocab_size(true)];
+/// Maps the weights in `idf` onto the vocabulary of `tokenizer`, returning
+/// the tokens of `idf` that are not in the vocabulary separately.
+fn idf_vector<'a>(
+    tokenizer: &Tokenizer,
+    idf: &'a HashMap<String, f32>,
+) -> (Vec<f32>, Vec<&'a str>) {
+    let mut idf_vector = vec![0.0; tokenizer.get_vocab_size(true)];
+    let mut unknown = vec![];
     for (token, weight) in idf {
-        let id = tokenizer
-            .token_to_id(&token)
-            .ok_or(anyhow!("Token not found"))?;
-        idf_tensor[id as usize] = weight;
+        match tokenizer.token_to_id(token) {
+            Some(id) => idf_vector[id as usize] = *weight,
+            None => unknown.push(token.as_str()),
+        }
     }
-    let res = Tensor::from_vec(idf_tensor, tokenizer.get_vocab_size(true), &ctx.device)?;
-    Ok(res)
+    (idf_vector, unknown)
 }
 
-impl MaskedLM for BertForMaskedLM {
-    type Config = candle_transformers::models::bert::Config;
-    const DTYPE: DType = candle_transformers::models::bert::DTYPE;
+fn unknown_tokens_message(mut unknown: Vec<&str>, total: usize) -> String {
+    unknown.sort_unstable();
+    let examples = unknown
+        .iter()
+        .take(5)
+        .map(|token| format!("{:?}", token))
+        .collect::<Vec<_>>()
+        .join(", ");
+    format!(
+        "{} of {} entries of idf.json are not in the vocabulary of the tokenizer ({}{})",
+        unknown.len(),
+        total,
+        examples,
+        if unknown.len() > 5 { ", ..." } else { "" }
+    )
+}
 
-    fn load(vb: VarBuilder, config: &Self::Config) -> Result<Self> {
-        BertForMaskedLM::load(vb, config).map_err(Error::msg)
+/// Checks that the files of the model in `path` can be loaded and agree with
+/// each other, returning the outcome of each check. The model is not kept.
+pub fn validate_model(path: &Path) -> Vec<(&'static str, Result<String>)> {
+    if path.join(remote::CONFIG_FILE).exists() {
+        let model = RemoteModel::load(path);
+        let endpoint = model
+            .as_ref()
+            .map_err(|e| anyhow!("{}", e))
+            .and_then(|model| {
+                model.encode_document("hello")?;
+                Ok(format!("{} responded", model.url()))
+            });
+        return vec![
+            ("config", model.map(|_| "remote model".to_string())),
+            ("endpoint", endpoint),
+        ];
     }
+    vec![
+        (
+            "config",
+            architecture(path).map(|architecture| format!("architecture {}", architecture)),
+        ),
+        (
+            "tokenizer",
+            load_tokenizer(path)
+                .map(|tokenizer| format!("{} tokens", tokenizer.get_vocab_size(true))),
+        ),
+        ("vocab_size", validate_vocab_size(path)),
+        ("weights", validate_weights(path)),
+        ("idf", validate_idf(path)),
+    ]
+}
 
-    fn forward(&self, input_ids: &Tensor, attention_mask: &Tensor) -> Result<Tensor> {
-        let token_type_ids = input_ids.zeros_like()?;
-        self.forward(input_ids, &token_type_ids, Some(attention_mask))
-            .map_err(Error::msg)
+fn validate_vocab_size(path: &Path) -> Result<String> {
+    let config: serde_json::Value = read_json(path, "config.json")?;
+    let config_size = config
+        .get("vocab_size")
+        .and_then(|v| v.as_u64())
+        .ok_or(anyhow!("Failed to get vocab_size from config.json"))?;
+    let tokenizer_size = load_tokenizer(path)?.get_vocab_size(true) as u64;
+    if config_size != tokenizer_size {
+        return Err(anyhow!(
+            "vocab_size of config.json is {}, but the tokenizer has {} tokens",
+            config_size,
+            tokenizer_size
+        ));
     }
+    Ok(format!("{} tokens", config_size))
+}
 
-    fn activation(vector: &Tensor) -> Result<Tensor> {
-        let one = Tensor::ones_like(vector)?;
-        let vector = vector.relu()?;
-        let vector = Tensor::log(&one.broadcast_add(&vector)?)?;
-        Ok(vector)
+fn validate_weights(path: &Path) -> Result<String> {
+    let architecture = architecture(path)?;
+    match architecture.as_str() {
+        "BertForMaskedLM" => compare_outputs::<Bert, BertForMaskedLM>(path)?,
+        "DistilBertForMaskedLM" => compare_outputs::<DistilBert, DistilBertForMaskedLM>(path)?,
+        _ => return Err(anyhow!("Unknown architecture: {}", architecture)),
     }
+    Ok(format!(
+        "{} weights loaded, outputs match candle_transformers",
+        architecture
+    ))
 }
 
-impl MaskedLM for DistilBertForMaskedLM {
-    type Config = candle_transformers::models::distilbert::Config;
-    const DTYPE: DType = candle_transformers::models::distilbert::DTYPE;
-
-    fn load(vb: VarBuilder, config: &Self::Config) -> Result<Self> {
-        DistilBertForMaskedLM::load(vb, config).map_err(Error::msg)
+/// Largest difference allowed between the logits of the two models.
+const OUTPUT_TOLERANCE: f32 = 1e-4;
+
+/// Loads the weights in `path` into model `T`, which encodes texts, and into
+/// `R` of `candle_transformers` that it is built from, and checks that they
+/// return the same logits for a sample text.
+fn compare_outputs<T: MaskedLM, R: MaskedLM>(path: &Path) -> Result<()> {
+    let ctx = LoadContext::new(path, T::DTYPE)?;
+    let model = load_weights::<T>(&ctx)?;
+    let reference = load_weights::<R>(&ctx)?;
+    let tokenizer = load_tokenizer(path)?;
+    let feature = tokenizer
+        .encode_fast("Currently New York is rainy.", true)
+        .map_err(Error::msg)?;
+    let shape = (1, feature.len());
+    let input_ids = Tensor::from_vec(feature.get_ids().to_vec(), shape, &ctx.device)?;
+    let attention_mask =
+        Tensor::from_vec(feature.get_attention_mask().to_vec(), shape, &ctx.device)?;
+    let difference = (model.forward(&input_ids, &attention_mask)?
+        - reference.forward(&input_ids, &attention_mask)?)?
+    .abs()?
+    .max_all()?
+    .to_dtype(DType::F32)?
+    .to_scalar::<f32>()?;
+    if difference.is_nan() || difference > OUTPUT_TOLERANCE {
+        return Err(anyhow!(
+            "Outputs differ from candle_transformers by up to {}",
+            difference
+        ));
     }
+    Ok(())
+}
 
-    fn forward(&self, input_ids: &Tensor, attention_mask: &Tensor) -> Result<Tensor> {
-        self.forward(input_ids, attention_mask).map_err(Error::msg)
+fn validate_idf(path: &Path) -> Result<String> {
+    if !path.join("idf.json").exists() {
+        return Ok("no idf.json, queries are encoded by inference".to_string());
     }
-
-    fn activation(vector: &Tensor) -> Result<Tensor> {
-        let one = Tensor::ones_like(vector)?;
-        let vector = vector.relu()?;
-        let vector = Tensor::log(&one.broadcast_add(&vector)?)?;
-        let vector = Tensor::log(&one.broadcast_add(&vector)?)?;
-        Ok(vector)
+    let tokenizer = load_tokenizer(path)?;
+    let idf: HashMap<String, f32> = read_json(path, "idf.json")?;
+    let (_, unknown) = idf_vector(&tokenizer, &idf);
+    if !unknown.is_empty() {
+        return Err(anyhow!(unknown_tokens_message(unknown, idf.len())));
     }
+    Ok(format!("{} entries", idf.len()))
 }
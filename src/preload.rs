@@ -0,0 +1,142 @@
+use std::{
+    collections::HashMap, num::NonZeroUsize, panic::AssertUnwindSafe, ptr::null_mut, time::Duration,
+};
+
+use pgrx::{
+    bgworkers::{BackgroundWorker, BackgroundWorkerBuilder, BgWorkerStartTime, SignalWakeFlags},
+    htup::heap_getattr_raw,
+    pg_guard, pg_sys, FromDatum,
+};
+
+/// `pg_db_role_setting`, which the bindings do not export.
+const DB_ROLE_SETTING_RELATION_ID: pg_sys::Oid = pg_sys::Oid::from_u32(2964);
+const RESTART_INTERVAL: Duration = Duration::from_secs(10);
+const GUC_NAME: &str = "splade.preload_models";
+
+pub fn init() {
+    BackgroundWorkerBuilder::new("pg_splade preload launcher")
+        .set_type("pg_splade preload launcher")
+        .set_library("pg_splade")
+        .set_function("splade_preload_launcher")
+        .enable_spi_access()
+        // Models stored in the database are replicated, so standbys preload too.
+        .set_start_time(BgWorkerStartTime::ConsistentState)
+        .set_restart_time(Some(RESTART_INTERVAL))
+        .load();
+}
+
+/// Returns the `splade.preload_models` set for each database by
+/// `ALTER DATABASE ... SET`.
+unsafe fn database_lists() -> HashMap<pg_sys::Oid, String> {
+    let mut lists = HashMap::new();
+    let rel = pg_sys::table_open(DB_ROLE_SETTING_RELATION_ID, pg_sys::AccessShareLock as _);
+    let scan = pg_sys::table_beginscan_catalog(rel, 0, null_mut());
+    loop {
+        let tuple = pg_sys::heap_getnext(scan, pg_sys::ScanDirection::ForwardScanDirection);
+        if tuple.is_null() {
+            break;
+        }
+        let attr = |attno: usize| {
+            heap_getattr_raw(tuple, NonZeroUsize::new(attno).unwrap(), (*rel).rd_att)
+        };
+        let database = attr(1).and_then(|d| pg_sys::Oid::from_datum(d, false));
+        let role = attr(2).and_then(|d| pg_sys::Oid::from_datum(d, false));
+        // Settings for a database and a role are applied at login only.
+        let (Some(database), Some(role)) = (database, role) else {
+            continue;
+        };
+        if role != pg_sys::InvalidOid {
+            continue;
+        }
+        let settings = attr(3)
+            .and_then(|d| Vec::<Option<String>>::from_datum(d, false))
+            .unwrap_or_default();
+        for setting in settings.into_iter().flatten() {
+            if let Some((name, value)) = setting.split_once('=') {
+                if name.eq_ignore_ascii_case(GUC_NAME) {
+                    lists.insert(database, value.to_string());
+                }
+            }
+        }
+    }
+    pg_sys::heap_endscan(scan);
+    pg_sys::table_close(rel, pg_sys::AccessShareLock as _);
+    lists
+}
+
+/// Starts a worker that preloads the models listed for `database`, or the
+/// server-wide list if it is invalid.
+fn start_worker(database: pg_sys::Oid) {
+    let worker = BackgroundWorkerBuilder::new("pg_splade preload")
+        .set_type("pg_splade preload")
+        .set_library("pg_splade")
+        .set_function("splade_preload_worker")
+        .set_argument(Some(database.into()))
+        .enable_spi_access()
+        .set_restart_time(None)
+        .load_dynamic();
+    if worker.is_err() {
+        pgrx::warning!("Could not start preload worker, consider increasing max_worker_processes");
+    }
+}
+
+/// Warms the server-wide list, which the postmaster loaded at startup, and
+/// preloads models again whenever the configuration is reloaded and a list
+/// has changed.
+#[pg_guard]
+#[no_mangle]
+pub extern "C" fn splade_preload_launcher(_arg: pg_sys::Datum) {
+    BackgroundWorker::attach_signal_handlers(SignalWakeFlags::SIGHUP | SignalWakeFlags::SIGTERM);
+    // Only shared catalogs are read, so no database is needed.
+    BackgroundWorker::connect_worker_to_spi(None, None);
+
+    let mut server = crate::guc::preload_models();
+    if !server.is_empty() {
+        start_worker(pg_sys::InvalidOid);
+    }
+    let mut databases = HashMap::new();
+    loop {
+        let lists = BackgroundWorker::transaction(|| unsafe { database_lists() });
+        for (database, models) in &lists {
+            if databases.get(database) != Some(models) && !models.trim().is_empty() {
+                start_worker(*database);
+            }
+        }
+        databases = lists;
+
+        loop {
+            if !BackgroundWorker::wait_latch(None) {
+                return;
+            }
+            if BackgroundWorker::sighup_received() {
+                break;
+            }
+        }
+        unsafe { pg_sys::ProcessConfigFile(pg_sys::GucContext::PGC_SIGHUP) };
+        let models = crate::guc::preload_models();
+        if models != server && !models.is_empty() {
+            start_worker(pg_sys::InvalidOid);
+        }
+        server = models;
+    }
+}
+
+/// Loads and warms the models listed for the database in `arg`. Models
+/// stored in the database are materialized into the local cache, and model
+/// files are paged in, so that sessions load them quickly.
+#[pg_guard]
+#[no_mangle]
+pub extern "C" fn splade_preload_worker(arg: pg_sys::Datum) {
+    BackgroundWorker::attach_signal_handlers(SignalWakeFlags::SIGTERM);
+
+    let database = pg_sys::Oid::from(arg.value() as u32);
+    if database == pg_sys::InvalidOid {
+        crate::encode::preload(crate::encode::get_filesystem_model);
+        return;
+    }
+    // The settings of the database apply once connected.
+    unsafe { pg_sys::BackgroundWorkerInitializeConnectionByOid(database, pg_sys::InvalidOid, 0) };
+    BackgroundWorker::transaction(AssertUnwindSafe(|| {
+        crate::encode::preload(crate::encode::get_model)
+    }));
+}
@@ -0,0 +1,287 @@
+use std::{
+    path::Path,
+    sync::mpsc::{self, RecvTimeoutError},
+    time::Duration,
+};
+
+use anyhow::{anyhow, bail, Result};
+use candle_core::{Device, Tensor};
+use pgrx::{pg_sys::panic::ErrorReport, Json};
+use serde_json::Value;
+use ureq::http::StatusCode;
+
+use crate::{
+    error::SqlError,
+    guc::QueryMode,
+    model::{Encode, Encoded, Profile},
+    storage,
+};
+
+/// File that marks a model as served by a remote endpoint.
+pub const CONFIG_FILE: &str = "remote.json";
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+/// How often cancellation is checked while waiting for a response.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Endpoint of a model served over HTTP, compatible with the `/embed_sparse`
+/// route of text-embeddings-inference.
+pub struct RemoteConfig {
+    url: String,
+    /// Endpoint for queries, if they are encoded by a different model.
+    query_url: Option<String>,
+    /// Dimensions of the vectors, the vocabulary size of the remote model.
+    dimensions: u32,
+    headers: Vec<(String, String)>,
+    timeout: Duration,
+}
+
+impl RemoteConfig {
+    pub fn parse(value: &Value) -> Result<Self> {
+        let object = value
+            .as_object()
+            .ok_or(anyhow!("{} must be a JSON object", CONFIG_FILE))?;
+        let string = |key: &str| -> Result<Option<String>> {
+            match object.get(key) {
+                None | Some(Value::Null) => Ok(None),
+                Some(Value::String(s)) => Ok(Some(s.clone())),
+                Some(_) => bail!("{} of {} must be a string", key, CONFIG_FILE),
+            }
+        };
+        let url = string("url")?.ok_or(anyhow!("{} has no url", CONFIG_FILE))?;
+        let query_url = string("query_url")?;
+        for url in std::iter::once(&url).chain(&query_url) {
+            if !url.starts_with("http://") && !url.starts_with("https://") {
+                bail!("Invalid endpoint {}, it must be an http or https URL", url);
+            }
+        }
+        let dimensions = object
+            .get("dimensions")
+            .and_then(|v| v.as_u64())
+            .filter(|&d| d > 0 && d <= u32::MAX as u64)
+            .ok_or(anyhow!(
+                "{} has no valid dimensions, the vocabulary size of the model",
+                CONFIG_FILE
+            ))? as u32;
+        let headers = match object.get("headers") {
+            None | Some(Value::Null) => vec![],
+            Some(Value::Object(headers)) => headers
+                .iter()
+                .map(|(k, v)| {
+                    v.as_str()
+                        .map(|v| (k.clone(), v.to_string()))
+                        .ok_or(anyhow!("Header {} of {} must be a string", k, CONFIG_FILE))
+                })
+                .collect::<Result<_>>()?,
+            Some(_) => bail!("headers of {} must be a JSON object", CONFIG_FILE),
+        };
+        let timeout = match object.get("timeout_ms") {
+            None | Some(Value::Null) => DEFAULT_TIMEOUT,
+            Some(v) => Duration::from_millis(v.as_u64().ok_or(anyhow!(
+                "timeout_ms of {} must be a positive integer",
+                CONFIG_FILE
+            ))?),
+        };
+        Ok(Self {
+            url,
+            query_url,
+            dimensions,
+            headers,
+            timeout,
+        })
+    }
+}
+
+pub struct RemoteModel {
+    config: RemoteConfig,
+}
+
+impl RemoteModel {
+    pub fn load(path: &Path) -> Result<Self> {
+        let value: Value = crate::model::read_json(path, CONFIG_FILE)?;
+        Ok(Self {
+            config: RemoteConfig::parse(&value)?,
+        })
+    }
+
+    pub fn url(&self) -> &str {
+        &self.config.url
+    }
+
+    /// Sends `texts` to `url` in one request and returns their vectors.
+    fn embed(&self, url: &str, texts: &[&str]) -> Result<Vec<Encoded>> {
+        if texts.is_empty() {
+            return Ok(vec![]);
+        }
+        let body = serde_json::json!({ "inputs": texts, "truncate": true });
+        let (status, body) = self.post(url, serde_json::to_vec(&body)?)?;
+        if !status.is_success() {
+            // text-embeddings-inference describes errors in an `error` field.
+            let message = serde_json::from_str::<Value>(&body)
+                .ok()
+                .and_then(|v| v.get("error").and_then(|e| e.as_str()).map(str::to_string))
+                .unwrap_or(body);
+            bail!("{} returned {}: {}", url, status, message);
+        }
+        let vectors = serde_json::from_str::<Value>(&body)
+            .ok()
+            .and_then(|v| v.as_array().cloned())
+            .ok_or(anyhow!(
+                "Invalid response of {}, expected a list of vectors",
+                url
+            ))?;
+        if vectors.len() != texts.len() {
+            bail!(
+                "{} returned {} vectors for {} texts",
+                url,
+                vectors.len(),
+                texts.len()
+            );
+        }
+        vectors
+            .iter()
+            .map(|vector| self.dense_vector(url, vector))
+            .collect()
+    }
+
+    /// Sends `body` to `url` and returns the status and body of the response.
+    /// The request runs in a thread, so that cancellation is checked while
+    /// waiting for it.
+    fn post(&self, url: &str, body: Vec<u8>) -> Result<(StatusCode, String)> {
+        let mut request = ureq::post(url)
+            .config()
+            .timeout_global(Some(self.config.timeout))
+            .http_status_as_error(false)
+            .build()
+            .header("Content-Type", "application/json");
+        for (name, value) in &self.config.headers {
+            request = request.header(name.as_str(), value.as_str());
+        }
+        let url = url.to_string();
+        let (sender, receiver) = mpsc::channel();
+        std::thread::spawn(move || {
+            let response = request
+                .send(body)
+                .map_err(|e| anyhow!("Failed to call {}: {}", url, e))
+                .and_then(|mut res| {
+                    let body = res
+                        .body_mut()
+                        .read_to_string()
+                        .map_err(|e| anyhow!("Failed to read response of {}: {}", url, e))?;
+                    Ok((res.status(), body))
+                });
+            // The receiver is gone if the query was cancelled.
+            let _ = sender.send(response);
+        });
+        loop {
+            match receiver.recv_timeout(POLL_INTERVAL) {
+                Ok(response) => return response,
+                Err(RecvTimeoutError::Timeout) => pgrx::check_for_interrupts!(),
+                Err(RecvTimeoutError::Disconnected) => bail!("Request to remote model failed"),
+            }
+        }
+    }
+
+    /// Converts a vector of `{"index": ..., "value": ...}` entries into a
+    /// dense tensor.
+    fn dense_vector(&self, url: &str, vector: &Value) -> Result<Encoded> {
+        let invalid = || {
+            anyhow!(
+                "Invalid response of {}, expected index and value pairs",
+                url
+            )
+        };
+        let mut dense = vec![0.0f32; self.config.dimensions as usize];
+        for entry in vector.as_array().ok_or_else(invalid)? {
+            let index = entry
+                .get("index")
+                .and_then(|v| v.as_u64())
+                .ok_or_else(invalid)?;
+            let value = entry
+                .get("value")
+                .and_then(|v| v.as_f64())
+                .ok_or_else(invalid)?;
+            if index >= self.config.dimensions as u64 {
+                bail!(
+                    "{} returned index {}, but the model has {} dimensions",
+                    url,
+                    index,
+                    self.config.dimensions
+                );
+            }
+            dense[index as usize] = value as f32;
+        }
+        // The endpoint tokenizes and truncates texts itself.
+        Ok(Encoded {
+            vector: Tensor::from_vec(dense, self.config.dimensions as usize, &Device::Cpu)?,
+            tokens: 0,
+            truncated: false,
+        })
+    }
+
+    fn query_url(&self, mode: QueryMode) -> Result<&str> {
+        if mode == QueryMode::idf {
+            bail!(
+                SqlError::invalid_parameter_value("Remote models have no idf.json")
+                    .with_hint("Set splade.query_mode to inference or auto")
+            );
+        }
+        Ok(self.config.query_url.as_deref().unwrap_or(&self.config.url))
+    }
+}
+
+impl Encode for RemoteModel {
+    fn encode_document(&self, document: &str) -> Result<Encoded> {
+        let mut vectors = self.embed(&self.config.url, &[document])?;
+        Ok(vectors.remove(0))
+    }
+
+    fn profile_document(&self, document: &str, profile: &mut Profile) -> Result<Encoded> {
+        let encoded = self.encode_document(document)?;
+        profile.finish("request");
+        Ok(encoded)
+    }
+
+    fn encode_query(&self, query: &str, mode: QueryMode) -> Result<Encoded> {
+        let mut vectors = self.embed(self.query_url(mode)?, &[query])?;
+        Ok(vectors.remove(0))
+    }
+
+    fn encode_documents(&self, documents: &[&str]) -> Result<Vec<Encoded>> {
+        self.embed(&self.config.url, documents)
+    }
+
+    fn encode_queries(&self, queries: &[&str], mode: QueryMode) -> Result<Vec<Encoded>> {
+        self.embed(self.query_url(mode)?, queries)
+    }
+}
+
+/// Creates model `name` that is served by the remote endpoint described by
+/// `config`.
+#[pgrx::pg_extern(volatile, strict)]
+fn create_remote_model(name: &str, config: Json) -> Result<(), ErrorReport> {
+    crate::error::sql(|| {
+        storage::check_model_name(name)?;
+        if crate::job::is_active(name) {
+            bail!(SqlError::object_in_use(format!(
+                "Model {} is already being downloaded",
+                name
+            )));
+        }
+        let staging = storage::staging_dir(name)?;
+        crate::encode::check_model_absent(name)?;
+        RemoteConfig::parse(&config.0)
+            .map_err(|e| SqlError::invalid_parameter_value(e.to_string()))?;
+
+        if staging.exists() {
+            std::fs::remove_dir_all(&staging)?;
+        }
+        std::fs::create_dir_all(&staging)?;
+        std::fs::write(
+            staging.join(CONFIG_FILE),
+            serde_json::to_vec_pretty(&config.0)?,
+        )?;
+        storage::install(name, &staging, crate::guc::model_storage())?;
+        crate::encode::invalidate(name);
+        Ok(())
+    })
+}
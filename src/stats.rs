@@ -0,0 +1,211 @@
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+use pgrx::{iter::TableIterator, name, pg_shmem_init, pg_sys, PGRXSharedMemory, PgLwLock};
+
+use crate::{
+    encode::EncodeKind,
+    job::{read_str, write_str},
+};
+
+/// Models beyond this number are not tracked until statistics are reset.
+const MAX_MODELS: usize = 128;
+const NAME_LEN: usize = 64;
+
+/// Statistics of a model. The counters are updated with the lock held in
+/// shared mode, so that encoding sessions don't wait for each other.
+struct ModelStats {
+    /// Name of the model, empty if the entry is unused. Only set with the
+    /// lock held exclusively.
+    name: [u8; NAME_LEN],
+    calls: AtomicU64,
+    documents: AtomicU64,
+    queries: AtomicU64,
+    tokens: AtomicU64,
+    truncated: AtomicU64,
+    total_time_us: AtomicU64,
+    max_time_us: AtomicU64,
+    loads: AtomicU64,
+    load_time_us: AtomicU64,
+}
+
+impl Default for ModelStats {
+    fn default() -> Self {
+        Self {
+            name: [0; NAME_LEN],
+            calls: AtomicU64::new(0),
+            documents: AtomicU64::new(0),
+            queries: AtomicU64::new(0),
+            tokens: AtomicU64::new(0),
+            truncated: AtomicU64::new(0),
+            total_time_us: AtomicU64::new(0),
+            max_time_us: AtomicU64::new(0),
+            loads: AtomicU64::new(0),
+            load_time_us: AtomicU64::new(0),
+        }
+    }
+}
+
+struct Stats {
+    models: [ModelStats; MAX_MODELS],
+}
+
+impl Default for Stats {
+    fn default() -> Self {
+        Self {
+            models: std::array::from_fn(|_| ModelStats::default()),
+        }
+    }
+}
+
+unsafe impl PGRXSharedMemory for Stats {}
+
+static STATS: PgLwLock<Stats> = PgLwLock::new();
+
+pub fn init() {
+    pg_shmem_init!(STATS);
+}
+
+fn find(stats: &Stats, model: &str) -> Option<usize> {
+    stats
+        .models
+        .iter()
+        .position(|entry| entry.name[0] != 0 && read_str(&entry.name) == model)
+}
+
+/// Updates the statistics of model `model` with `update`. The lock is only
+/// taken exclusively to add the model. Statistics are only kept in processes
+/// started by the postmaster after shared memory is set up.
+fn update(model: &str, update: impl FnOnce(&ModelStats)) {
+    if !crate::preloaded() || !unsafe { pg_sys::IsUnderPostmaster } {
+        return;
+    }
+    {
+        let stats = STATS.share();
+        if let Some(index) = find(&stats, model) {
+            update(&stats.models[index]);
+            return;
+        }
+        if stats.models.iter().all(|entry| entry.name[0] != 0) {
+            return;
+        }
+    }
+    let mut stats = STATS.exclusive();
+    let index =
+        find(&stats, model).or_else(|| stats.models.iter().position(|entry| entry.name[0] == 0));
+    let Some(index) = index else {
+        return;
+    };
+    let entry = &mut stats.models[index];
+    if entry.name[0] == 0 {
+        write_str(&mut entry.name, model);
+    }
+    update(entry);
+}
+
+/// Records that `texts` texts were encoded by model `model` in one call that
+/// took `elapsed`, reading `tokens` tokens of which `truncated` texts were
+/// cut to the maximum length of the model.
+pub fn record_encode(
+    model: &str,
+    kind: EncodeKind,
+    texts: usize,
+    tokens: usize,
+    truncated: usize,
+    elapsed: Duration,
+) {
+    let elapsed = elapsed.as_micros() as u64;
+    update(model, |entry| {
+        entry.calls.fetch_add(1, Ordering::Relaxed);
+        match kind {
+            EncodeKind::Document => entry.documents.fetch_add(texts as u64, Ordering::Relaxed),
+            EncodeKind::Query(_) => entry.queries.fetch_add(texts as u64, Ordering::Relaxed),
+        };
+        entry.tokens.fetch_add(tokens as u64, Ordering::Relaxed);
+        entry
+            .truncated
+            .fetch_add(truncated as u64, Ordering::Relaxed);
+        entry.total_time_us.fetch_add(elapsed, Ordering::Relaxed);
+        entry.max_time_us.fetch_max(elapsed, Ordering::Relaxed);
+    });
+}
+
+/// Records that model `model` was loaded from storage in `elapsed`.
+pub fn record_load(model: &str, elapsed: Duration) {
+    let elapsed = elapsed.as_micros() as u64;
+    update(model, |entry| {
+        entry.loads.fetch_add(1, Ordering::Relaxed);
+        entry.load_time_us.fetch_add(elapsed, Ordering::Relaxed);
+    });
+}
+
+fn count(counter: &AtomicU64) -> i64 {
+    counter.load(Ordering::Relaxed) as i64
+}
+
+fn millis(us: &AtomicU64) -> f64 {
+    us.load(Ordering::Relaxed) as f64 / 1000.0
+}
+
+#[allow(clippy::type_complexity)]
+#[pgrx::pg_extern(volatile)]
+fn pg_stat_splade_internal() -> TableIterator<
+    'static,
+    (
+        name!(model, String),
+        name!(calls, i64),
+        name!(documents, i64),
+        name!(queries, i64),
+        name!(tokens, i64),
+        name!(truncated, i64),
+        name!(total_time, f64),
+        name!(max_time, f64),
+        name!(loads, i64),
+        name!(load_time, f64),
+    ),
+> {
+    if !crate::preloaded() {
+        return TableIterator::new(vec![]);
+    }
+    let stats = STATS.share();
+    let mut rows = stats
+        .models
+        .iter()
+        .filter(|entry| entry.name[0] != 0)
+        .map(|entry| {
+            (
+                read_str(&entry.name),
+                count(&entry.calls),
+                count(&entry.documents),
+                count(&entry.queries),
+                count(&entry.tokens),
+                count(&entry.truncated),
+                millis(&entry.total_time_us),
+                millis(&entry.max_time_us),
+                count(&entry.loads),
+                millis(&entry.load_time_us),
+            )
+        })
+        .collect::<Vec<_>>();
+    rows.sort_by(|a, b| a.0.cmp(&b.0));
+    TableIterator::new(rows)
+}
+
+/// Resets the statistics of all models.
+#[pgrx::pg_extern(volatile)]
+fn pg_stat_splade_reset() {
+    if !crate::preloaded() {
+        return;
+    }
+    *STATS.exclusive() = Stats::default();
+}
+
+pgrx::extension_sql!(
+    r#"
+CREATE VIEW pg_stat_splade AS SELECT * FROM pg_stat_splade_internal();
+"#,
+    name = "pg_stat_splade",
+    requires = [pg_stat_splade_internal]
+);
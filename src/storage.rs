@@ -0,0 +1,570 @@
+use std::{
+    ffi::CStr,
+    fs::{File, OpenOptions},
+    hash::{DefaultHasher, Hash, Hasher},
+    io::{Read, Write},
+    ops::Deref,
+    path::{Path, PathBuf},
+    sync::LazyLock,
+};
+
+use anyhow::{anyhow, bail, Result};
+use pgrx::{pg_sys, pg_sys::panic::ErrorReport, spi, PgMemoryContexts, Spi};
+
+use crate::{error::SqlError, guc::ModelStorage};
+
+static SHARE_DIR: LazyLock<PathBuf> = LazyLock::new(|| {
+    let mut sharepath = [0u8; pgrx::pg_sys::MAXPGPATH as usize];
+    unsafe {
+        #[allow(static_mut_refs)]
+        pgrx::pg_sys::get_share_path(
+            pgrx::pg_sys::my_exec_path.as_ptr(),
+            sharepath.as_mut_ptr().cast(),
+        )
+    };
+    let sharepath = CStr::from_bytes_until_nul(&sharepath).unwrap();
+    let sharepath = sharepath.to_str().unwrap();
+    PathBuf::from(sharepath)
+});
+
+/// Expands a leading `$sharedir` in a directory setting.
+fn expand_dir(dir: &str) -> PathBuf {
+    match dir.strip_prefix("$sharedir") {
+        Some(rest) => PathBuf::from(format!("{}{}", SHARE_DIR.display(), rest)),
+        None => PathBuf::from(dir),
+    }
+}
+
+/// Directory models are downloaded, imported into and deleted from.
+pub static ASSETS_DIR: LazyLock<PathBuf> = LazyLock::new(|| expand_dir(&crate::guc::model_dir()));
+
+/// Directories models are loaded from, in order: `ASSETS_DIR`, then the
+/// directories of `splade.model_search_path`, which may be read-only.
+static SEARCH_PATH: LazyLock<Vec<PathBuf>> = LazyLock::new(|| {
+    let mut dirs = vec![ASSETS_DIR.clone()];
+    for dir in crate::guc::model_search_path() {
+        let dir = expand_dir(&dir);
+        if !dirs.contains(&dir) {
+            dirs.push(dir);
+        }
+    }
+    dirs
+});
+
+/// Local copies of models stored in the database, in a directory named by
+/// the version of each model, as databases can have models of the same name.
+static CACHE_DIR: LazyLock<PathBuf> = LazyLock::new(|| ASSETS_DIR.join(".cache"));
+
+fn cache_dir(version: &str) -> PathBuf {
+    CACHE_DIR.join(version)
+}
+
+const VERSION_FILE: &str = ".version";
+const CHUNK_SIZE: usize = 1024 * 1024;
+const MAX_NAME_LEN: usize = 63;
+/// Distinguishes the locks of staging directories from advisory locks taken
+/// with SQL functions, which use 1 and 2.
+const STAGING_LOCK_SPACE: u16 = 0x5350;
+
+pgrx::extension_sql!(
+    r#"
+CREATE TABLE splade_models (
+    name text PRIMARY KEY,
+    id uuid NOT NULL DEFAULT gen_random_uuid(),
+    created_at timestamptz NOT NULL DEFAULT now()
+);
+CREATE TABLE splade_model_files (
+    model text NOT NULL REFERENCES splade_models (name) ON DELETE CASCADE,
+    file text NOT NULL,
+    seq int NOT NULL,
+    data bytea NOT NULL,
+    PRIMARY KEY (model, file, seq)
+);
+SELECT pg_catalog.pg_extension_config_dump('splade_models', '');
+SELECT pg_catalog.pg_extension_config_dump('splade_model_files', '');
+"#,
+    name = "splade_model_tables"
+);
+
+/// Model names are used as directory names, so they are restricted to a
+/// conservative set of characters and cannot start with a dot.
+pub fn check_model_name(name: &str) -> Result<()> {
+    let valid = (1..=MAX_NAME_LEN).contains(&name.len())
+        && name.starts_with(|c: char| c.is_ascii_alphanumeric())
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.'));
+    if !valid {
+        bail!(SqlError::invalid_parameter_value(format!(
+            "Invalid model name {:?}: it must be 1 to {} ASCII letters, digits, '_', '-' or '.', starting with a letter or digit",
+            name,
+            MAX_NAME_LEN
+        )));
+    }
+    Ok(())
+}
+
+pub fn model_dir(name: &str) -> Result<PathBuf> {
+    check_model_name(name)?;
+    Ok(ASSETS_DIR.join(name))
+}
+
+/// Returns the first directory of the search path that contains model
+/// `name`.
+pub fn find_model_dir(name: &str) -> Result<Option<PathBuf>> {
+    check_model_name(name)?;
+    Ok(SEARCH_PATH
+        .iter()
+        .map(|dir| dir.join(name))
+        .find(|path| path.exists()))
+}
+
+/// Directory a model is downloaded into before it is installed, locked
+/// against other processes creating the same model until it is dropped.
+pub struct Staging {
+    path: PathBuf,
+    tag: pg_sys::LOCKTAG,
+}
+
+impl Deref for Staging {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl AsRef<Path> for Staging {
+    fn as_ref(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for Staging {
+    fn drop(&mut self) {
+        unsafe { pg_sys::LockRelease(&self.tag, pg_sys::ExclusiveLock as _, true) };
+    }
+}
+
+/// Locks and returns the staging directory of model `name`. Staging
+/// directories are shared by all databases, and so is the lock, which is an
+/// advisory lock that is not bound to a database or transaction.
+pub fn staging_dir(name: &str) -> Result<Staging> {
+    check_model_name(name)?;
+    let mut hasher = DefaultHasher::new();
+    name.hash(&mut hasher);
+    let hash = hasher.finish();
+    let tag = pg_sys::LOCKTAG {
+        locktag_field1: pg_sys::InvalidOid.as_u32(),
+        locktag_field2: (hash >> 32) as u32,
+        locktag_field3: hash as u32,
+        locktag_field4: STAGING_LOCK_SPACE,
+        locktag_type: pg_sys::LockTagType::LOCKTAG_ADVISORY as u8,
+        locktag_lockmethodid: pg_sys::USER_LOCKMETHOD as u8,
+    };
+    let result = unsafe { pg_sys::LockAcquire(&tag, pg_sys::ExclusiveLock as _, true, true) };
+    if result == pg_sys::LockAcquireResult::LOCKACQUIRE_NOT_AVAIL {
+        bail!(SqlError::object_in_use(format!(
+            "Model {} is being created by another session",
+            name
+        )));
+    }
+    Ok(Staging {
+        path: ASSETS_DIR.join(format!(".{}.download", name)),
+        tag,
+    })
+}
+
+/// Fully qualified name of the extension table `table`, or `None` if the
+/// extension is not installed in the current database.
+pub(crate) fn table(table: &str) -> Result<Option<String>> {
+    if !unsafe { pg_sys::IsTransactionState() } {
+        return Ok(None);
+    }
+    let schema = Spi::connect(|client| {
+        client
+            .select(
+                "SELECT (SELECT n.nspname::text FROM pg_catalog.pg_extension e \
+                 JOIN pg_catalog.pg_namespace n ON n.oid = e.extnamespace \
+                 WHERE e.extname = 'pg_splade')",
+                Some(1),
+                &[],
+            )?
+            .first()
+            .get_one::<String>()
+    })?;
+    Ok(schema.map(|schema| spi::quote_qualified_identifier(schema.as_str(), table)))
+}
+
+fn database_version(name: &str) -> Result<Option<String>> {
+    let Some(models) = table("splade_models")? else {
+        return Ok(None);
+    };
+    let version = Spi::connect(|client| {
+        client
+            .select(
+                &format!("SELECT (SELECT id::text FROM {} WHERE name = $1)", models),
+                Some(1),
+                &[name.into()],
+            )?
+            .first()
+            .get_one::<String>()
+    })?;
+    Ok(version)
+}
+
+pub fn exists(name: &str) -> Result<bool> {
+    Ok(find_model_dir(name)?.is_some() || database_version(name)?.is_some())
+}
+
+/// Returns the directory to load model `name` from, materializing it from the
+/// database into the local cache if needed.
+pub fn locate(name: &str) -> Result<Option<PathBuf>> {
+    if let Some(path) = find_model_dir(name)? {
+        return Ok(Some(path));
+    }
+    let Some(version) = database_version(name)? else {
+        return Ok(None);
+    };
+    let cache = cache_dir(&version);
+    if !is_materialized(&cache) {
+        let (_, files) = tables()?;
+        as_owner(&files, || materialize(name, &version))?;
+    }
+    Ok(Some(cache))
+}
+
+/// Runs `f` as the owner of table `table`. Only `splade_admin` can read
+/// `splade_model_files`, as remote models keep credentials in it, but every
+/// session that encodes with a model stored there must materialize it.
+fn as_owner<T>(table: &str, f: impl FnOnce() -> Result<T>) -> Result<T> {
+    let owner = Spi::get_one_with_args::<pg_sys::Oid>(
+        "SELECT relowner FROM pg_catalog.pg_class WHERE oid = $1::regclass",
+        &[table.into()],
+    )?
+    .ok_or(anyhow!("Table {} does not exist", table))?;
+    let mut user = pg_sys::InvalidOid;
+    let mut context = 0;
+    unsafe {
+        pg_sys::GetUserIdAndSecContext(&mut user, &mut context);
+        // Errors raised meanwhile restore the user when aborting.
+        pg_sys::SetUserIdAndSecContext(
+            owner,
+            context
+                | pg_sys::SECURITY_LOCAL_USERID_CHANGE as i32
+                | pg_sys::SECURITY_RESTRICTED_OPERATION as i32,
+        );
+    }
+    let result = f();
+    unsafe { pg_sys::SetUserIdAndSecContext(user, context) };
+    result
+}
+
+/// Whether all the files of a model were written into `dir`, which is the
+/// last step of materializing it.
+fn is_materialized(dir: &Path) -> bool {
+    dir.join(VERSION_FILE).exists()
+}
+
+/// Removes the local copy of version `version` of a model stored in the
+/// database.
+fn forget(version: &str) -> Result<()> {
+    let cache = cache_dir(version);
+    if cache.exists() {
+        std::fs::remove_dir_all(&cache)?;
+    }
+    Ok(())
+}
+
+pub fn list() -> Result<Vec<String>> {
+    let mut models = vec![];
+    for dir in SEARCH_PATH.iter() {
+        let Ok(entries) = std::fs::read_dir(dir) else {
+            continue;
+        };
+        for entry in entries.flatten() {
+            if entry.file_type().map(|ft| ft.is_dir()).unwrap_or(false) {
+                // Skip staging directories and the cache of database models.
+                if let Some(name) = entry.file_name().to_str().filter(|n| !n.starts_with('.')) {
+                    models.push(name.to_string());
+                }
+            }
+        }
+    }
+    if let Some(models_table) = table("splade_models")? {
+        Spi::connect(|client| {
+            let rows = client.select(&format!("SELECT name FROM {}", models_table), None, &[])?;
+            for row in rows {
+                if let Some(name) = row.get::<String>(1)? {
+                    models.push(name);
+                }
+            }
+            Ok::<_, spi::Error>(())
+        })?;
+    }
+    models.sort();
+    models.dedup();
+    Ok(models)
+}
+
+/// Installs the files downloaded into `staging` as model `name`.
+pub fn install(name: &str, staging: &Path, storage: ModelStorage) -> Result<()> {
+    check_model_name(name)?;
+    match storage {
+        ModelStorage::filesystem => std::fs::rename(staging, model_dir(name)?)?,
+        ModelStorage::database => {
+            let version = upload(name, staging)?;
+            std::fs::write(staging.join(VERSION_FILE), &version)?;
+            std::fs::create_dir_all(&*CACHE_DIR)?;
+            std::fs::rename(staging, cache_dir(&version))?;
+        }
+    }
+    Ok(())
+}
+
+pub fn delete(name: &str) -> Result<()> {
+    // Locked so that the staging directory of a running download is not
+    // removed under it.
+    let staging = staging_dir(name)?;
+    let mut found = false;
+    // The row goes first, so that files are only removed once it is deleted.
+    if let Some(models) = table("splade_models")? {
+        let version = Spi::connect_mut(|client| {
+            let deleted = client.update(
+                &format!("DELETE FROM {} WHERE name = $1 RETURNING id::text", models),
+                None,
+                &[name.into()],
+            )?;
+            if deleted.is_empty() {
+                return Ok(None);
+            }
+            deleted.first().get_one::<String>()
+        })?;
+        if let Some(version) = version {
+            forget(&version)?;
+            found = true;
+        }
+    }
+    let path = model_dir(name)?;
+    if path.exists() {
+        std::fs::remove_dir_all(&path)?;
+        found = true;
+    }
+    // Left behind by a download that failed and was not retried.
+    if staging.exists() {
+        std::fs::remove_dir_all(&staging)?;
+        found = true;
+    }
+    if !found {
+        if let Some(path) = find_model_dir(name)? {
+            bail!(
+                "Model {} is in {}, which is on splade.model_search_path and is not managed by pg_splade",
+                name,
+                path.display()
+            );
+        }
+        bail!(SqlError::undefined_object(format!(
+            "Model {} does not exist",
+            name
+        )));
+    }
+    Ok(())
+}
+
+fn tables() -> Result<(String, String)> {
+    match (table("splade_models")?, table("splade_model_files")?) {
+        (Some(models), Some(files)) => Ok((models, files)),
+        _ => bail!("Extension pg_splade is not installed in the current database"),
+    }
+}
+
+/// Writes the files in `dir` into the database as model `name`, returning the
+/// version of the stored model.
+fn upload(name: &str, dir: &Path) -> Result<String> {
+    let (models, files) = tables()?;
+    let version = Spi::get_one_with_args::<String>(
+        &format!(
+            "INSERT INTO {} (name) VALUES ($1) RETURNING id::text",
+            models
+        ),
+        &[name.into()],
+    )?
+    .ok_or(anyhow!("Failed to register model {}", name))?;
+
+    let insert = format!(
+        "INSERT INTO {} (model, file, seq, data) VALUES ($1, $2, $3, $4)",
+        files
+    );
+    let mut memcx = PgMemoryContexts::new("pg_splade model upload");
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let file = entry.file_name();
+        let Some(file) = file.to_str().filter(|f| !f.starts_with('.')) else {
+            continue;
+        };
+        if !entry.file_type()?.is_file() {
+            continue;
+        }
+        let mut reader = File::open(entry.path())?;
+        let mut seq = 0i32;
+        loop {
+            let mut chunk = Vec::with_capacity(CHUNK_SIZE);
+            (&mut reader)
+                .take(CHUNK_SIZE as u64)
+                .read_to_end(&mut chunk)?;
+            if chunk.is_empty() && seq > 0 {
+                break;
+            }
+            unsafe {
+                memcx.switch_to(|_| {
+                    Spi::run_with_args(
+                        &insert,
+                        &[
+                            name.into(),
+                            file.into(),
+                            seq.into(),
+                            chunk.as_slice().into(),
+                        ],
+                    )
+                })?;
+                memcx.reset();
+            }
+            pgrx::check_for_interrupts!();
+            seq += 1;
+            if chunk.len() < CHUNK_SIZE {
+                break;
+            }
+        }
+    }
+    Ok(version)
+}
+
+/// Writes the files of model `name` stored in the database into the local
+/// cache directory.
+fn materialize(name: &str, version: &str) -> Result<()> {
+    let (_, files) = tables()?;
+    let chunks = Spi::connect(|client| {
+        let rows = client.select(
+            &format!(
+                "SELECT file, seq FROM {} WHERE model = $1 ORDER BY file, seq",
+                files
+            ),
+            None,
+            &[name.into()],
+        )?;
+        let mut chunks = vec![];
+        for row in rows {
+            if let (Some(file), Some(seq)) = (row.get::<String>(1)?, row.get::<i32>(2)?) {
+                chunks.push((file, seq));
+            }
+        }
+        Ok::<_, spi::Error>(chunks)
+    })?;
+
+    let staging = CACHE_DIR.join(format!(".{}.{}", version, std::process::id()));
+    if staging.exists() {
+        std::fs::remove_dir_all(&staging)?;
+    }
+    std::fs::create_dir_all(&staging)?;
+
+    let select = format!(
+        "SELECT data FROM {} WHERE model = $1 AND file = $2 AND seq = $3",
+        files
+    );
+    for (file, seq) in chunks {
+        check_file_name(&file)?;
+        let data = Spi::connect(|client| {
+            let row = client
+                .select(
+                    &select,
+                    Some(1),
+                    &[name.into(), file.as_str().into(), seq.into()],
+                )?
+                .first();
+            let Some(datum) = row.get_datum_by_ordinal(1)? else {
+                return Ok(vec![]);
+            };
+            // Detoast in the SPI memory context, which is freed on disconnect,
+            // so that large models do not pile up in the transaction context.
+            let data = unsafe {
+                pgrx::varlena::varlena_to_byte_slice(pg_sys::pg_detoast_datum_packed(
+                    datum.cast_mut_ptr(),
+                ))
+            };
+            Ok::<_, spi::Error>(data.to_vec())
+        })?;
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(staging.join(&file))?
+            .write_all(&data)?;
+        pgrx::check_for_interrupts!();
+    }
+    std::fs::write(staging.join(VERSION_FILE), version)?;
+
+    let cache = cache_dir(version);
+    if let Err(e) = std::fs::rename(&staging, &cache) {
+        std::fs::remove_dir_all(&staging)?;
+        // Another backend may have materialized the same version meanwhile.
+        if !is_materialized(&cache) {
+            return Err(e.into());
+        }
+    }
+    Ok(())
+}
+
+pub fn check_file_name(file: &str) -> Result<()> {
+    if file.is_empty() || file.starts_with('.') || file.contains('/') {
+        bail!(SqlError::invalid_parameter_value(format!(
+            "Invalid model file name {:?}",
+            file
+        )));
+    }
+    Ok(())
+}
+
+/// Appends `data` to `file` of model `name`, creating the model if it does not
+/// exist yet. Large files can be uploaded in several calls.
+#[pgrx::pg_extern(volatile, strict)]
+fn import_model(name: &str, file: &str, data: &[u8]) -> Result<(), ErrorReport> {
+    crate::error::sql(|| {
+        check_model_name(name)?;
+        check_file_name(file)?;
+        match crate::guc::model_storage() {
+            ModelStorage::filesystem => {
+                let dir = model_dir(name)?;
+                std::fs::create_dir_all(&dir)?;
+                OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(dir.join(file))?
+                    .write_all(data)?;
+            }
+            ModelStorage::database => {
+                let (models, files) = tables()?;
+                if let Some(version) = database_version(name)? {
+                    forget(&version)?;
+                }
+                Spi::run_with_args(
+                    &format!(
+                        "INSERT INTO {} (name) VALUES ($1) \
+                     ON CONFLICT (name) DO UPDATE SET id = gen_random_uuid()",
+                        models
+                    ),
+                    &[name.into()],
+                )?;
+                Spi::run_with_args(
+                    &format!(
+                        "INSERT INTO {0} (model, file, seq, data) \
+                     SELECT $1, $2, coalesce(max(seq) + 1, 0), $3 FROM {0} \
+                     WHERE model = $1 AND file = $2",
+                        files
+                    ),
+                    &[name.into(), file.into(), data.into()],
+                )?;
+            }
+        }
+        crate::encode::invalidate(name);
+        Ok(())
+    })
+}
@@ -0,0 +1,134 @@
+use std::path::Path;
+
+use anyhow::{anyhow, bail, Error, Result};
+use serde_json::{Map, Value};
+use tokenizers::{
+    decoders::wordpiece::WordPiece as WordPieceDecoder, models::wordpiece::WordPiece,
+    normalizers::BertNormalizer, pre_tokenizers::bert::BertPreTokenizer,
+    processors::bert::BertProcessing, AddedToken, PaddingParams, PaddingStrategy, Tokenizer,
+    TruncationParams, TruncationStrategy,
+};
+
+/// Loads the tokenizer of the model in `path` from `tokenizer.json`, or builds
+/// a WordPiece tokenizer from `vocab.txt` for checkpoints that predate it.
+pub fn load_tokenizer(path: &Path) -> Result<Tokenizer> {
+    let file = path.join("tokenizer.json");
+    let mut tokenizer = if file.exists() {
+        Tokenizer::from_file(file).map_err(Error::msg)?
+    } else {
+        wordpiece_tokenizer(path)?
+    };
+    if let Some(pp) = tokenizer.get_padding_mut() {
+        pp.strategy = PaddingStrategy::BatchLongest;
+    } else {
+        tokenizer.with_padding(Some(PaddingParams {
+            strategy: PaddingStrategy::BatchLongest,
+            ..Default::default()
+        }));
+    }
+    if let Some(tr) = tokenizer.get_truncation_mut() {
+        tr.strategy = TruncationStrategy::LongestFirst;
+    } else {
+        tokenizer
+            .with_truncation(Some(TruncationParams {
+                strategy: TruncationStrategy::LongestFirst,
+                ..Default::default()
+            }))
+            .map_err(Error::msg)?;
+    }
+    Ok(tokenizer)
+}
+
+fn read_json_object(path: &Path) -> Result<Map<String, Value>> {
+    if !path.exists() {
+        return Ok(Map::new());
+    }
+    let content = std::fs::read_to_string(path)?;
+    match serde_json::from_str(&content)? {
+        Value::Object(object) => Ok(object),
+        _ => bail!("{} is not a JSON object", path.display()),
+    }
+}
+
+/// Reads special token `key`, which is either a plain string or an
+/// `AddedToken` object with a `content` field.
+fn special_token(config: &Map<String, Value>, key: &str, default: &str) -> String {
+    match config.get(key) {
+        Some(Value::String(token)) => token.clone(),
+        Some(Value::Object(token)) => token
+            .get("content")
+            .and_then(|v| v.as_str())
+            .unwrap_or(default)
+            .to_string(),
+        _ => default.to_string(),
+    }
+}
+
+/// Builds a BERT WordPiece tokenizer from `vocab.txt`, configured by
+/// `tokenizer_config.json` and `special_tokens_map.json` if they exist.
+fn wordpiece_tokenizer(path: &Path) -> Result<Tokenizer> {
+    let vocab = path.join("vocab.txt");
+    if !vocab.exists() {
+        bail!("Model has neither tokenizer.json nor vocab.txt");
+    }
+    let mut config = read_json_object(&path.join("tokenizer_config.json"))?;
+    config.extend(read_json_object(&path.join("special_tokens_map.json"))?);
+
+    let flag = |key: &str| config.get(key).and_then(|v| v.as_bool());
+    let lowercase = flag("do_lower_case").unwrap_or(true);
+    let strip_accents = flag("strip_accents");
+    let chinese_chars = flag("tokenize_chinese_chars").unwrap_or(true);
+    let unk = special_token(&config, "unk_token", "[UNK]");
+    let sep = special_token(&config, "sep_token", "[SEP]");
+    let pad = special_token(&config, "pad_token", "[PAD]");
+    let cls = special_token(&config, "cls_token", "[CLS]");
+    let mask = special_token(&config, "mask_token", "[MASK]");
+    let max_length = config
+        .get("model_max_length")
+        .and_then(|v| v.as_u64())
+        .filter(|&len| len <= 1 << 16)
+        .map(|len| len as usize);
+
+    let vocab = vocab
+        .to_str()
+        .ok_or(anyhow!("Invalid path {}", vocab.display()))?;
+    let model = WordPiece::from_file(vocab)
+        .unk_token(unk.clone())
+        .build()
+        .map_err(Error::msg)?;
+    let mut tokenizer = Tokenizer::new(model);
+    tokenizer
+        .with_normalizer(Some(BertNormalizer::new(
+            true,
+            chinese_chars,
+            strip_accents,
+            lowercase,
+        )))
+        .with_pre_tokenizer(Some(BertPreTokenizer))
+        .with_decoder(Some(WordPieceDecoder::default()));
+    let special_tokens =
+        [&unk, &sep, &pad, &cls, &mask].map(|token| AddedToken::from(token.as_str(), true));
+    tokenizer.add_special_tokens(&special_tokens);
+
+    let id = |token: &str| {
+        tokenizer
+            .token_to_id(token)
+            .ok_or(anyhow!("Token {} not found in vocab.txt", token))
+    };
+    let (sep_id, cls_id, pad_id) = (id(&sep)?, id(&cls)?, id(&pad)?);
+    tokenizer.with_post_processor(Some(BertProcessing::new((sep, sep_id), (cls, cls_id))));
+    tokenizer.with_padding(Some(PaddingParams {
+        pad_id,
+        pad_token: pad,
+        ..Default::default()
+    }));
+    if let Some(max_length) = max_length {
+        tokenizer
+            .with_truncation(Some(TruncationParams {
+                max_length,
+                ..Default::default()
+            }))
+            .map_err(Error::msg)?;
+    }
+    Ok(tokenizer)
+}
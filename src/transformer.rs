@@ -0,0 +1,309 @@
+//! BERT and DistilBERT masked language models that run their encoder layer by
+//! layer, so that a long forward pass can be cancelled between layers. The
+//! models of `candle_transformers` run all layers in one call, so their
+//! embeddings, layers and heads are reused where they are public.
+//! `splade_validate_model` checks that the outputs match theirs.
+
+use anyhow::{Error, Result};
+use candle_core::{DType, Tensor, D};
+use candle_nn::{layer_norm, linear, LayerNorm, Linear, Module, VarBuilder};
+use candle_transformers::models::{
+    bert::{self, BertEncoder, BertForMaskedLM, BertModel, BertOnlyMLMHead},
+    distilbert::{self, DistilBertForMaskedLM, DistilBertModel, DistilBertOnlyMLMHead},
+};
+use serde::Deserialize;
+
+use crate::model::MaskedLM;
+
+/// Runs each of `layers` on `hidden_states` with `run`, checking for
+/// cancellation before each of them.
+fn forward_layers<L>(
+    layers: &[L],
+    mut hidden_states: Tensor,
+    run: impl Fn(&L, &Tensor) -> candle_core::Result<Tensor>,
+) -> Result<Tensor> {
+    for layer in layers {
+        pgrx::check_for_interrupts!();
+        hidden_states = run(layer, &hidden_states)?;
+    }
+    Ok(hidden_states)
+}
+
+fn bert_activation(vector: &Tensor) -> Result<Tensor> {
+    let one = Tensor::ones_like(vector)?;
+    let vector = vector.relu()?;
+    let vector = Tensor::log(&one.broadcast_add(&vector)?)?;
+    Ok(vector)
+}
+
+fn distilbert_activation(vector: &Tensor) -> Result<Tensor> {
+    let one = Tensor::ones_like(vector)?;
+    let vector = vector.relu()?;
+    let vector = Tensor::log(&one.broadcast_add(&vector)?)?;
+    let vector = Tensor::log(&one.broadcast_add(&vector)?)?;
+    Ok(vector)
+}
+
+pub struct Bert {
+    /// The model without layers, which only computes the embeddings.
+    embeddings: BertModel,
+    /// An encoder for each layer, as the layers of `BertEncoder` can only be
+    /// run through it.
+    layers: Vec<BertEncoder>,
+    cls: BertOnlyMLMHead,
+}
+
+/// Turns `attention_mask` into the mask added to the attention scores of
+/// BERT, the lowest value for padding and zero for tokens, as `BertModel`
+/// does.
+fn extended_attention_mask(attention_mask: &Tensor, dtype: DType) -> candle_core::Result<Tensor> {
+    let attention_mask = attention_mask.unsqueeze(1)?.unsqueeze(1)?.to_dtype(dtype)?;
+    (attention_mask.ones_like()? - &attention_mask)?.broadcast_mul(
+        &Tensor::try_from(f32::MIN)?
+            .to_device(attention_mask.device())?
+            .to_dtype(dtype)?,
+    )
+}
+
+impl MaskedLM for Bert {
+    type Config = bert::Config;
+    const DTYPE: DType = bert::DTYPE;
+
+    fn load(vb: VarBuilder, config: &Self::Config) -> Result<Self> {
+        let embeddings = BertModel::load(
+            vb.pp("bert"),
+            &bert::Config {
+                num_hidden_layers: 0,
+                ..config.clone()
+            },
+        )?;
+        let encoder = BertEncoder::load(vb.pp("bert.encoder"), config)?;
+        let mut template = encoder.clone();
+        template.layers.clear();
+        let layers = encoder
+            .layers
+            .into_iter()
+            .map(|layer| {
+                let mut single = template.clone();
+                single.layers.push(layer);
+                single
+            })
+            .collect();
+        Ok(Self {
+            embeddings,
+            layers,
+            cls: BertOnlyMLMHead::load(vb.pp("cls"), config)?,
+        })
+    }
+
+    fn forward(&self, input_ids: &Tensor, attention_mask: &Tensor) -> Result<Tensor> {
+        let embeddings =
+            self.embeddings
+                .forward(input_ids, &input_ids.zeros_like()?, Some(attention_mask))?;
+        let attention_mask = extended_attention_mask(attention_mask, embeddings.dtype())?;
+        let sequence_output = forward_layers(&self.layers, embeddings, |layer, xs| {
+            layer.forward(xs, &attention_mask)
+        })?;
+        Ok(self.cls.forward(&sequence_output)?)
+    }
+
+    fn activation(vector: &Tensor) -> Result<Tensor> {
+        bert_activation(vector)
+    }
+}
+
+impl MaskedLM for BertForMaskedLM {
+    type Config = bert::Config;
+    const DTYPE: DType = bert::DTYPE;
+
+    fn load(vb: VarBuilder, config: &Self::Config) -> Result<Self> {
+        BertForMaskedLM::load(vb, config).map_err(Error::msg)
+    }
+
+    fn forward(&self, input_ids: &Tensor, attention_mask: &Tensor) -> Result<Tensor> {
+        let token_type_ids = input_ids.zeros_like()?;
+        self.forward(input_ids, &token_type_ids, Some(attention_mask))
+            .map_err(Error::msg)
+    }
+
+    fn activation(vector: &Tensor) -> Result<Tensor> {
+        bert_activation(vector)
+    }
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum DistilBertActivation {
+    Gelu,
+    Relu,
+}
+
+impl Module for DistilBertActivation {
+    fn forward(&self, xs: &Tensor) -> candle_core::Result<Tensor> {
+        match self {
+            Self::Gelu => xs.gelu(),
+            Self::Relu => xs.relu(),
+        }
+    }
+}
+
+/// The fields of the `config.json` of DistilBERT that its layers use, as
+/// those of `distilbert::Config` are private.
+#[derive(Debug, Clone, Deserialize)]
+struct LayerConfig {
+    dim: usize,
+    n_layers: usize,
+    n_heads: usize,
+    hidden_dim: usize,
+    activation: DistilBertActivation,
+}
+
+const DISTILBERT_LAYER_NORM_EPS: f64 = 1e-12;
+
+/// A layer of DistilBERT, which `candle_transformers` keeps private.
+struct TransformerBlock {
+    q_lin: Linear,
+    k_lin: Linear,
+    v_lin: Linear,
+    out_lin: Linear,
+    n_heads: usize,
+    sa_layer_norm: LayerNorm,
+    lin1: Linear,
+    lin2: Linear,
+    activation: DistilBertActivation,
+    output_layer_norm: LayerNorm,
+}
+
+impl TransformerBlock {
+    fn load(vb: VarBuilder, config: &LayerConfig) -> Result<Self> {
+        let attention = vb.pp("attention");
+        let ffn = vb.pp("ffn");
+        Ok(Self {
+            q_lin: linear(config.dim, config.dim, attention.pp("q_lin"))?,
+            k_lin: linear(config.dim, config.dim, attention.pp("k_lin"))?,
+            v_lin: linear(config.dim, config.dim, attention.pp("v_lin"))?,
+            out_lin: linear(config.dim, config.dim, attention.pp("out_lin"))?,
+            n_heads: config.n_heads,
+            sa_layer_norm: layer_norm(
+                config.dim,
+                DISTILBERT_LAYER_NORM_EPS,
+                vb.pp("sa_layer_norm"),
+            )?,
+            lin1: linear(config.dim, config.hidden_dim, ffn.pp("lin1"))?,
+            lin2: linear(config.hidden_dim, config.dim, ffn.pp("lin2"))?,
+            activation: config.activation,
+            output_layer_norm: layer_norm(
+                config.dim,
+                DISTILBERT_LAYER_NORM_EPS,
+                vb.pp("output_layer_norm"),
+            )?,
+        })
+    }
+
+    /// Runs the block, with `mask` set for the padding positions.
+    fn forward(&self, hidden_states: &Tensor, mask: &Tensor) -> candle_core::Result<Tensor> {
+        let (bs, q_length, dim) = hidden_states.dims3()?;
+        let dim_per_head = dim / self.n_heads;
+        let heads = |lin: &Linear| {
+            lin.forward(hidden_states)?
+                .reshape((bs, q_length, self.n_heads, dim_per_head))?
+                .transpose(1, 2)
+        };
+        let (q, k, v) = (
+            heads(&self.q_lin)?,
+            heads(&self.k_lin)?,
+            heads(&self.v_lin)?,
+        );
+
+        let q = (q / (dim_per_head as f64).sqrt())?;
+        let scores = q.matmul(&k.transpose(2, 3)?.contiguous()?)?;
+        let scores = scores.to_dtype(DType::F32)?;
+        let mask = mask.broadcast_as(scores.shape())?;
+        let on_true =
+            Tensor::new(f32::NEG_INFINITY, scores.device())?.broadcast_as(mask.shape())?;
+        let scores = mask.where_cond(&on_true, &scores)?;
+        let weights = candle_nn::ops::softmax(&scores, D::Minus1)?;
+        let context = weights
+            .matmul(&v.contiguous()?)?
+            .transpose(1, 2)?
+            .reshape((bs, q_length, dim))?
+            .contiguous()?;
+        let sa_output = self.out_lin.forward(&context)?;
+
+        let sa_output = sa_output.broadcast_add(hidden_states)?;
+        let sa_output = self.sa_layer_norm.forward(&sa_output)?;
+        let ffn_output = sa_output
+            .apply(&self.lin1)?
+            .apply(&self.activation)?
+            .apply(&self.lin2)?;
+        self.output_layer_norm.forward(&(&ffn_output + sa_output)?)
+    }
+}
+
+pub struct DistilBert {
+    /// The model without layers, which only computes the embeddings.
+    embeddings: DistilBertModel,
+    layers: Vec<TransformerBlock>,
+    cls: DistilBertOnlyMLMHead,
+}
+
+impl MaskedLM for DistilBert {
+    /// The `config.json` as is, as it is read into `distilbert::Config`
+    /// without layers for the embeddings, and into `LayerConfig`.
+    type Config = serde_json::Value;
+    const DTYPE: DType = distilbert::DTYPE;
+
+    fn load(vb: VarBuilder, config: &Self::Config) -> Result<Self> {
+        let layer_config: LayerConfig = serde_json::from_value(config.clone())?;
+        let mut embeddings_config = config.clone();
+        embeddings_config["n_layers"] = 0.into();
+        let embeddings = DistilBertModel::load(
+            vb.pp("distilbert"),
+            &serde_json::from_value(embeddings_config)?,
+        )?;
+        let layers = (0..layer_config.n_layers)
+            .map(|i| {
+                TransformerBlock::load(
+                    vb.pp(format!("distilbert.transformer.layer.{i}")),
+                    &layer_config,
+                )
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Self {
+            embeddings,
+            layers,
+            cls: DistilBertOnlyMLMHead::load(vb, &serde_json::from_value(config.clone())?)?,
+        })
+    }
+
+    fn forward(&self, input_ids: &Tensor, attention_mask: &Tensor) -> Result<Tensor> {
+        let embeddings = self.embeddings.forward(input_ids, attention_mask)?;
+        let (bs, seq_len) = attention_mask.dims2()?;
+        let mask = attention_mask.eq(0u32)?.reshape((bs, 1, 1, seq_len))?;
+        let sequence_output = forward_layers(&self.layers, embeddings, |layer, xs| {
+            layer.forward(xs, &mask)
+        })?;
+        Ok(self.cls.forward(&sequence_output)?)
+    }
+
+    fn activation(vector: &Tensor) -> Result<Tensor> {
+        distilbert_activation(vector)
+    }
+}
+
+impl MaskedLM for DistilBertForMaskedLM {
+    type Config = distilbert::Config;
+    const DTYPE: DType = distilbert::DTYPE;
+
+    fn load(vb: VarBuilder, config: &Self::Config) -> Result<Self> {
+        DistilBertForMaskedLM::load(vb, config).map_err(Error::msg)
+    }
+
+    fn forward(&self, input_ids: &Tensor, attention_mask: &Tensor) -> Result<Tensor> {
+        self.forward(input_ids, attention_mask).map_err(Error::msg)
+    }
+
+    fn activation(vector: &Tensor) -> Result<Tensor> {
+        distilbert_activation(vector)
+    }
+}
@@ -0,0 +1,546 @@
+use std::{
+    cell::RefCell,
+    path::Path,
+    ptr::null_mut,
+    sync::atomic::{AtomicBool, Ordering},
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, bail, Result};
+use pgrx::{
+    bgworkers::{BackgroundWorker, BackgroundWorkerBuilder, BgWorkerStartTime, SignalWakeFlags},
+    pg_guard, pg_shmem_init, pg_sys, PGRXSharedMemory, PgLwLock, PgMemoryContexts, PgSqlErrorCode,
+};
+
+use crate::{
+    datatype::SparsevecOwned,
+    encode::EncodeKind,
+    error::{model_not_found, SqlError},
+    guc::QueryMode,
+};
+
+pub const MAX_WORKERS: usize = 32;
+/// Sessions waiting for a worker to attach to their queues.
+const MAX_PENDING: usize = 64;
+/// Size of each of the two queues between a session and a worker. Larger
+/// messages are streamed through the queue.
+const QUEUE_SIZE: usize = 256 * 1024;
+const IDLE_TIMEOUT: Duration = Duration::from_secs(1);
+const RESTART_INTERVAL: Duration = Duration::from_secs(5);
+
+const WORKER_EXITED: &str = "Inference worker exited while encoding";
+
+#[derive(Clone, Copy)]
+struct WorkerSlot {
+    pid: i32,
+    pending: [pg_sys::dsm_handle; MAX_PENDING],
+    npending: usize,
+}
+
+impl Default for WorkerSlot {
+    fn default() -> Self {
+        Self {
+            pid: 0,
+            pending: [0; MAX_PENDING],
+            npending: 0,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Default)]
+struct Workers {
+    /// Worker the next session connects to, to spread sessions evenly.
+    next: usize,
+    slots: [WorkerSlot; MAX_WORKERS],
+}
+
+unsafe impl PGRXSharedMemory for Workers {}
+
+static WORKERS: PgLwLock<Workers> = PgLwLock::new();
+
+static IS_INFERENCE_WORKER: AtomicBool = AtomicBool::new(false);
+
+pub fn init() {
+    pg_shmem_init!(WORKERS);
+    for i in 0..crate::guc::inference_workers() {
+        BackgroundWorkerBuilder::new(&format!("pg_splade inference worker {}", i))
+            .set_type("pg_splade inference worker")
+            .set_library("pg_splade")
+            .set_function("splade_inference_worker")
+            .set_argument(Some(pg_sys::Datum::from(i as i64)))
+            .enable_shmem_access(None)
+            .set_start_time(BgWorkerStartTime::PostmasterStart)
+            .set_restart_time(Some(RESTART_INTERVAL))
+            .load();
+    }
+}
+
+/// Whether this session sends texts to the inference workers instead of
+/// loading models itself.
+pub fn enabled() -> bool {
+    crate::guc::inference_workers() > 0 && !IS_INFERENCE_WORKER.load(Ordering::Relaxed)
+}
+
+unsafe fn send(mqh: *mut pg_sys::shm_mq_handle, data: &[u8]) -> bool {
+    #[cfg(feature = "pg14")]
+    let result = pg_sys::shm_mq_send(mqh, data.len(), data.as_ptr().cast(), false);
+    #[cfg(not(feature = "pg14"))]
+    let result = pg_sys::shm_mq_send(mqh, data.len(), data.as_ptr().cast(), false, true);
+    result == pg_sys::shm_mq_result::SHM_MQ_SUCCESS
+}
+
+/// Queues of a session: requests flow from the session to the worker, and
+/// responses back.
+struct Connection {
+    segment: *mut pg_sys::dsm_segment,
+    request: *mut pg_sys::shm_mq_handle,
+    response: *mut pg_sys::shm_mq_handle,
+    seq: u64,
+    /// Set while a request is in flight. A connection left busy by an error
+    /// may hold a partial message, so it is replaced.
+    busy: bool,
+}
+
+thread_local! {
+    static CONNECTION: RefCell<Option<Connection>> = const { RefCell::new(None) };
+}
+
+impl Connection {
+    fn open() -> Result<Self> {
+        let connection = unsafe {
+            PgMemoryContexts::TopMemoryContext.switch_to(|_| {
+                let segment = pg_sys::dsm_create(2 * QUEUE_SIZE, 0);
+                // Keep the segment for the whole session, not the transaction.
+                pg_sys::dsm_pin_mapping(segment);
+                let base = pg_sys::dsm_segment_address(segment).cast::<u8>();
+                let request = pg_sys::shm_mq_create(base.cast(), QUEUE_SIZE);
+                pg_sys::shm_mq_set_sender(request, pg_sys::MyProc);
+                let response = pg_sys::shm_mq_create(base.add(QUEUE_SIZE).cast(), QUEUE_SIZE);
+                pg_sys::shm_mq_set_receiver(response, pg_sys::MyProc);
+                Connection {
+                    segment,
+                    request: pg_sys::shm_mq_attach(request, segment, null_mut()),
+                    response: pg_sys::shm_mq_attach(response, segment, null_mut()),
+                    seq: 0,
+                    busy: false,
+                }
+            })
+        };
+        let handle = unsafe { pg_sys::dsm_segment_handle(connection.segment) };
+        if let Err(e) = register(handle) {
+            unsafe { pg_sys::dsm_detach(connection.segment) };
+            return Err(e);
+        }
+        Ok(connection)
+    }
+
+    /// Sends `request` and waits for the response with the same sequence
+    /// number. Responses to requests abandoned by an earlier error are skipped.
+    unsafe fn call(&mut self, seq: u64, request: &[u8]) -> Result<Vec<u8>> {
+        if !send(self.request, request) {
+            bail!(SqlError::external_routine_exception(WORKER_EXITED));
+        }
+        loop {
+            let mut nbytes = 0;
+            let mut data = null_mut();
+            let result = pg_sys::shm_mq_receive(self.response, &mut nbytes, &mut data, false);
+            if result != pg_sys::shm_mq_result::SHM_MQ_SUCCESS {
+                bail!(SqlError::external_routine_exception(WORKER_EXITED));
+            }
+            let message = std::slice::from_raw_parts(data.cast::<u8>(), nbytes);
+            if Reader(message).u64().ok() == Some(seq) {
+                return Ok(message.to_vec());
+            }
+        }
+    }
+}
+
+/// Hands the queues in segment `handle` to a running worker.
+fn register(handle: pg_sys::dsm_handle) -> Result<()> {
+    let pid = {
+        let mut workers = WORKERS.exclusive();
+        let n = crate::guc::inference_workers();
+        let start = workers.next;
+        workers.next = (start + 1) % n;
+        let Some(i) = (0..n)
+            .map(|k| (start + k) % n)
+            .find(|&i| workers.slots[i].pid != 0 && workers.slots[i].npending < MAX_PENDING)
+        else {
+            bail!("No inference worker is available");
+        };
+        let slot = &mut workers.slots[i];
+        slot.pending[slot.npending] = handle;
+        slot.npending += 1;
+        slot.pid
+    };
+    unsafe {
+        let proc = pg_sys::BackendPidGetProc(pid);
+        if !proc.is_null() {
+            pg_sys::SetLatch(&mut (*proc).procLatch);
+        }
+    }
+    Ok(())
+}
+
+/// Encodes `text` with model `model` in an inference worker.
+pub fn encode(model: &str, text: &str, kind: EncodeKind) -> Result<SparsevecOwned> {
+    // The session locates the model, so that models stored in the database
+    // are materialized from its own database.
+    let Some(path) = crate::storage::locate(model)? else {
+        bail!(model_not_found(model));
+    };
+    let path = path
+        .to_str()
+        .ok_or(anyhow!("Invalid path {}", path.display()))?;
+    CONNECTION.with(|connection| {
+        let mut connection = connection.borrow_mut();
+        if connection.as_ref().is_some_and(|c| c.busy) {
+            if let Some(stale) = connection.take() {
+                unsafe { pg_sys::dsm_detach(stale.segment) };
+            }
+        }
+        if connection.is_none() {
+            *connection = Some(Connection::open()?);
+        }
+        let connection = connection.as_mut().unwrap();
+        connection.seq += 1;
+        let request = Request {
+            seq: connection.seq,
+            kind,
+            model,
+            path,
+            text,
+        };
+        connection.busy = true;
+        let response = unsafe { connection.call(request.seq, &request.to_bytes())? };
+        connection.busy = false;
+        decode_response(&response)
+    })
+}
+
+struct Request<'a> {
+    seq: u64,
+    kind: EncodeKind,
+    model: &'a str,
+    path: &'a str,
+    text: &'a str,
+}
+
+fn kind_to_byte(kind: EncodeKind) -> u8 {
+    match kind {
+        EncodeKind::Document => 0,
+        EncodeKind::Query(QueryMode::auto) => 1,
+        EncodeKind::Query(QueryMode::idf) => 2,
+        EncodeKind::Query(QueryMode::inference) => 3,
+    }
+}
+
+fn kind_from_byte(byte: u8) -> Result<EncodeKind> {
+    match byte {
+        0 => Ok(EncodeKind::Document),
+        1 => Ok(EncodeKind::Query(QueryMode::auto)),
+        2 => Ok(EncodeKind::Query(QueryMode::idf)),
+        3 => Ok(EncodeKind::Query(QueryMode::inference)),
+        _ => bail!("Malformed inference request"),
+    }
+}
+
+impl<'a> Request<'a> {
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(32 + self.path.len() + self.text.len());
+        buf.extend_from_slice(&self.seq.to_le_bytes());
+        buf.push(kind_to_byte(self.kind));
+        for s in [self.model, self.path] {
+            buf.extend_from_slice(&(s.len() as u32).to_le_bytes());
+            buf.extend_from_slice(s.as_bytes());
+        }
+        buf.extend_from_slice(self.text.as_bytes());
+        buf
+    }
+
+    fn from_bytes(message: &'a [u8]) -> Result<Self> {
+        let mut reader = Reader(message);
+        let seq = reader.u64()?;
+        let kind = kind_from_byte(reader.take(1)?[0])?;
+        let len = reader.u32()? as usize;
+        let model = reader.str(len)?;
+        let len = reader.u32()? as usize;
+        let path = reader.str(len)?;
+        let text = reader.str(reader.0.len())?;
+        Ok(Self {
+            seq,
+            kind,
+            model,
+            path,
+            text,
+        })
+    }
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8]> {
+        if self.0.len() < n {
+            bail!("Malformed inference message");
+        }
+        let (head, tail) = self.0.split_at(n);
+        self.0 = tail;
+        Ok(head)
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into()?))
+    }
+
+    fn u64(&mut self) -> Result<u64> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into()?))
+    }
+
+    fn str(&mut self, n: usize) -> Result<&'a str> {
+        Ok(std::str::from_utf8(self.take(n)?)?)
+    }
+}
+
+fn encode_response(seq: u64, result: Result<SparsevecOwned>) -> Vec<u8> {
+    let mut buf = seq.to_le_bytes().to_vec();
+    match result {
+        Ok(vector) => {
+            let vector = vector.as_borrowed();
+            buf.push(0);
+            buf.extend_from_slice(&vector.dims().to_le_bytes());
+            buf.extend_from_slice(&(vector.len() as u32).to_le_bytes());
+            for index in vector.indexes() {
+                buf.extend_from_slice(&index.to_le_bytes());
+            }
+            for value in vector.values() {
+                buf.extend_from_slice(&value.to_le_bytes());
+            }
+        }
+        Err(e) => {
+            // The error is sent with its SQLSTATE, DETAIL and HINT, so that
+            // the session raises it as if it had encoded the text itself.
+            let error = SqlError::of(&e);
+            buf.push(1);
+            buf.extend_from_slice(&(error.code as i32).to_le_bytes());
+            for s in [Some(error.message), error.detail, error.hint] {
+                let s = s.unwrap_or_default();
+                buf.extend_from_slice(&(s.len() as u32).to_le_bytes());
+                buf.extend_from_slice(s.as_bytes());
+            }
+        }
+    }
+    buf
+}
+
+fn decode_response(message: &[u8]) -> Result<SparsevecOwned> {
+    let mut reader = Reader(message);
+    reader.u64()?;
+    if reader.take(1)?[0] != 0 {
+        let code = PgSqlErrorCode::from(reader.u32()? as i32);
+        let mut read = || -> Result<Option<String>> {
+            let len = reader.u32()? as usize;
+            Ok(Some(reader.str(len)?.to_string()).filter(|s| !s.is_empty()))
+        };
+        bail!(SqlError {
+            code,
+            message: read()?.unwrap_or_default(),
+            detail: read()?,
+            hint: read()?,
+        });
+    }
+    let dims = reader.u32()?;
+    let nnz = reader.u32()? as usize;
+    let indexes = (0..nnz).map(|_| reader.u32()).collect::<Result<Vec<_>>>()?;
+    let values = (0..nnz)
+        .map(|_| Ok(f32::from_bits(reader.u32()?)))
+        .collect::<Result<Vec<_>>>()?;
+    SparsevecOwned::new_checked(dims, indexes, values)
+}
+
+/// Queues of a session, seen from the worker.
+struct Client {
+    segment: *mut pg_sys::dsm_segment,
+    request: *mut pg_sys::shm_mq_handle,
+    response: *mut pg_sys::shm_mq_handle,
+    detached: bool,
+}
+
+impl Client {
+    unsafe fn attach(handle: pg_sys::dsm_handle) -> Option<Self> {
+        PgMemoryContexts::TopMemoryContext.switch_to(|_| {
+            let segment = pg_sys::dsm_attach(handle);
+            if segment.is_null() {
+                // The session exited before the worker got to it.
+                return None;
+            }
+            pg_sys::dsm_pin_mapping(segment);
+            let base = pg_sys::dsm_segment_address(segment).cast::<u8>();
+            let request = base.cast::<pg_sys::shm_mq>();
+            pg_sys::shm_mq_set_receiver(request, pg_sys::MyProc);
+            let response = base.add(QUEUE_SIZE).cast::<pg_sys::shm_mq>();
+            pg_sys::shm_mq_set_sender(response, pg_sys::MyProc);
+            Some(Client {
+                segment,
+                request: pg_sys::shm_mq_attach(request, segment, null_mut()),
+                response: pg_sys::shm_mq_attach(response, segment, null_mut()),
+                detached: false,
+            })
+        })
+    }
+
+    /// Returns the next request of the session, if there is one.
+    unsafe fn receive(&mut self) -> Option<Vec<u8>> {
+        let mut nbytes = 0;
+        let mut data = null_mut();
+        match pg_sys::shm_mq_receive(self.request, &mut nbytes, &mut data, true) {
+            pg_sys::shm_mq_result::SHM_MQ_SUCCESS => {
+                Some(std::slice::from_raw_parts(data.cast::<u8>(), nbytes).to_vec())
+            }
+            pg_sys::shm_mq_result::SHM_MQ_WOULD_BLOCK => None,
+            _ => {
+                self.detached = true;
+                None
+            }
+        }
+    }
+
+    unsafe fn respond(&mut self, response: &[u8]) {
+        if !send(self.response, response) {
+            self.detached = true;
+        }
+    }
+}
+
+/// Adds the requests waiting in the queues of `clients` to `batch`, up to
+/// `limit` requests. The scan starts at client `next`, after the last one
+/// scanned before, so that no session waits behind the others for long.
+fn receive(
+    clients: &mut [Client],
+    batch: &mut Vec<(usize, Vec<u8>)>,
+    limit: usize,
+    next: &mut usize,
+) {
+    let count = clients.len();
+    let start = *next;
+    for offset in 0..count {
+        if batch.len() >= limit {
+            break;
+        }
+        let i = (start + offset) % count;
+        let client = &mut clients[i];
+        // Sessions wait for each response, so a session with a request in
+        // the batch has nothing else queued.
+        if client.detached || batch.iter().any(|(j, _)| *j == i) {
+            continue;
+        }
+        if let Some(message) = unsafe { client.receive() } {
+            batch.push((i, message));
+            *next = (i + 1) % count;
+        }
+    }
+}
+
+/// Encodes the texts of a batch of requests, running the texts for the same
+/// model and kind through the model together.
+fn handle_batch(batch: &[(usize, Vec<u8>)]) -> Vec<Vec<u8>> {
+    let mut responses = vec![vec![]; batch.len()];
+    let mut groups: Vec<((&str, &str, EncodeKind), Vec<(usize, Request)>)> = vec![];
+    for (i, (_, message)) in batch.iter().enumerate() {
+        let request = match Request::from_bytes(message) {
+            Ok(request) => request,
+            Err(e) => {
+                responses[i] = encode_response(0, Err(e));
+                continue;
+            }
+        };
+        let key = (request.model, request.path, request.kind);
+        match groups.iter_mut().find(|(k, _)| *k == key) {
+            Some((_, requests)) => requests.push((i, request)),
+            None => groups.push((key, vec![(i, request)])),
+        }
+    }
+    for ((model, path, kind), requests) in groups {
+        let texts = requests.iter().map(|(_, r)| r.text).collect::<Vec<_>>();
+        let results = match crate::encode::get_model_at(model, Path::new(path)) {
+            Ok(ptr) => crate::encode::encode_batch_with(model, &ptr, &texts, kind),
+            Err(e) => texts.iter().map(|_| Err(SqlError::of(&e).into())).collect(),
+        };
+        for ((i, request), result) in requests.into_iter().zip(results) {
+            responses[i] = encode_response(request.seq, result);
+        }
+    }
+    responses
+}
+
+fn take_pending(index: usize) -> Vec<pg_sys::dsm_handle> {
+    let mut workers = WORKERS.exclusive();
+    let slot = &mut workers.slots[index];
+    let pending = slot.pending[..slot.npending].to_vec();
+    slot.npending = 0;
+    pending
+}
+
+#[pg_guard]
+#[no_mangle]
+pub extern "C" fn splade_inference_worker(arg: pg_sys::Datum) {
+    BackgroundWorker::attach_signal_handlers(SignalWakeFlags::SIGHUP | SignalWakeFlags::SIGTERM);
+    IS_INFERENCE_WORKER.store(true, Ordering::Relaxed);
+
+    let index = arg.value();
+    // Sessions registered while the worker was restarting stay pending.
+    WORKERS.exclusive().slots[index].pid = unsafe { pg_sys::MyProcPid };
+
+    crate::encode::preload(crate::encode::get_filesystem_model_at);
+
+    let mut clients: Vec<Client> = vec![];
+    let mut next = 0;
+    let mut running = true;
+    while running {
+        if BackgroundWorker::sighup_received() {
+            unsafe { pg_sys::ProcessConfigFile(pg_sys::GucContext::PGC_SIGHUP) };
+            crate::encode::preload(crate::encode::get_filesystem_model_at);
+        }
+        for handle in take_pending(index) {
+            if let Some(client) = unsafe { Client::attach(handle) } {
+                clients.push(client);
+            }
+        }
+
+        let max_batch_size = crate::guc::max_batch_size();
+        let mut batch = vec![];
+        receive(&mut clients, &mut batch, max_batch_size, &mut next);
+        if !batch.is_empty() {
+            // Give other sessions a moment to send their requests, so that
+            // they are encoded together.
+            let deadline = Instant::now() + crate::guc::batch_wait();
+            while batch.len() < max_batch_size {
+                let now = Instant::now();
+                if now >= deadline {
+                    break;
+                }
+                if !BackgroundWorker::wait_latch(Some(deadline - now)) {
+                    running = false;
+                    break;
+                }
+                receive(&mut clients, &mut batch, max_batch_size, &mut next);
+            }
+            for ((i, _), response) in batch.iter().zip(handle_batch(&batch)) {
+                unsafe { clients[*i].respond(&response) };
+            }
+            // Blocking sends reset the latch too, so poll again without
+            // waiting, as there may be more work.
+            running &= !BackgroundWorker::sigterm_received();
+        } else {
+            running = BackgroundWorker::wait_latch(Some(IDLE_TIMEOUT));
+        }
+
+        clients.retain(|client| {
+            if client.detached {
+                unsafe { pg_sys::dsm_detach(client.segment) };
+            }
+            !client.detached
+        });
+    }
+    WORKERS.exclusive().slots[index].pid = 0;
+}
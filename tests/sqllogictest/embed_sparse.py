@@ -0,0 +1,56 @@
+"""Stub of the /embed_sparse route of text-embeddings-inference for the remote
+model tests. The i-th word of each input is returned as index 1000 + i with
+value 0.5. Requests without the expected Authorization header are rejected.
+
+Usage: python3 embed_sparse.py PORT
+
+The server detaches once it listens, and exits after a minute without
+requests.
+"""
+
+import json
+import os
+import sys
+from http.server import BaseHTTPRequestHandler, HTTPServer
+
+
+class Handler(BaseHTTPRequestHandler):
+    def do_POST(self):
+        if self.headers.get("Authorization") != "Bearer secret":
+            self.reply(401, {"error": "Invalid credentials"})
+            return
+        body = json.loads(self.rfile.read(int(self.headers["Content-Length"])))
+        vectors = [
+            [{"index": 1000 + i, "value": 0.5} for i, _ in enumerate(text.split())]
+            for text in body["inputs"]
+        ]
+        self.reply(200, vectors)
+
+    def reply(self, status, value):
+        body = json.dumps(value).encode()
+        self.send_response(status)
+        self.send_header("Content-Type", "application/json")
+        self.send_header("Content-Length", str(len(body)))
+        self.end_headers()
+        self.wfile.write(body)
+
+    def log_message(self, format, *args):
+        pass
+
+
+def main():
+    server = HTTPServer(("127.0.0.1", int(sys.argv[1])), Handler)
+    server.timeout = 60
+    if os.fork():
+        return
+    os.setsid()
+    while True:
+        server.timed_out = False
+        server.handle_timeout = lambda: setattr(server, "timed_out", True)
+        server.handle_request()
+        if server.timed_out:
+            break
+
+
+if __name__ == "__main__":
+    main()